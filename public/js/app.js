@@ -22,6 +22,10 @@ let colors;
 let selectedColor = -1;
 let socket;
 let localCooldown = 0;
+let canvasSeq = 0;
+let socketAuthenticated = false;
+let drawId = 0;
+let pendingDraws = new Map();
 
 async function initApp() {
     await initPalette();
@@ -33,12 +37,30 @@ async function initApp() {
 
 function initSocket() {
     let wsProtocol = window.location.protocol === 'https:' ? 'wss' : 'ws';
-    socket = new WebSocket(`${wsProtocol}://${window.location.host}/api/ws`);
+    socket = new WebSocket(`${wsProtocol}://${window.location.host}/api/ws?format=binary&batch=100`);
+    socket.binaryType = 'arraybuffer';
+
+    socket.onopen = authenticateSocket;
+
+    socket.onclose = function() {
+        socketAuthenticated = false;
+    }
 
     socket.onmessage = function(event) {
-        let data = JSON.parse(event.data);
-        ctx.fillStyle = colors[data.color];
-        ctx.fillRect(data.x, data.y, 1, 1);
+        if (typeof event.data === 'string') {
+            handleServerMessage(JSON.parse(event.data));
+            return;
+        }
+
+        let view = new DataView(event.data);
+        for (let offset = 0; offset + 9 <= view.byteLength; offset += 9) {
+            let seq = view.getUint32(offset, true);
+            if (seq <= canvasSeq % 2 ** 32) continue;
+            let x = view.getUint16(offset + 4, true);
+            let y = view.getUint16(offset + 6, true);
+            ctx.fillStyle = colors[view.getUint8(offset + 8)];
+            ctx.fillRect(x, y, 1, 1);
+        }
     }
 
     socket.onerror = function(error) {
@@ -46,17 +68,89 @@ function initSocket() {
     }
 }
 
+function authenticateSocket() {
+    let token = localStorage.getItem("token");
+    if (token !== null && socket.readyState === WebSocket.OPEN) {
+        socket.send(JSON.stringify({ type: 'auth', token: token }));
+    }
+}
+
+function handleServerMessage(message) {
+    switch (message.type) {
+        case 'authenticated':
+            socketAuthenticated = true;
+            break;
+        case 'ack': {
+            let resolve = pendingDraws.get(message.id);
+            pendingDraws.delete(message.id);
+            if (resolve) resolve(message);
+            break;
+        }
+        case 'resize':
+            resizeCanvas(message.width, message.height, message.blank);
+            break;
+        case 'palette':
+            renderPalette(message);
+            break;
+        case 'error':
+            console.error(message.message);
+            if (!socketAuthenticated && message.message.includes('token')) {
+                refreshSession();
+            }
+            break;
+    }
+}
+
+function resizeCanvas(width, height, blank) {
+    let previous = ctx.getImageData(0, 0, canvas.width, canvas.height);
+    canvas.width = width;
+    canvas.height = height;
+    ctx.fillStyle = colors[blank];
+    ctx.fillRect(0, 0, width, height);
+    ctx.putImageData(previous, 0, 0);
+}
+
+function sendPixelOverSocket() {
+    let id = ++drawId;
+    return new Promise(resolve => {
+        pendingDraws.set(id, resolve);
+        socket.send(JSON.stringify({
+            type: 'draw',
+            id: id,
+            x: oldPixel.x,
+            y: oldPixel.y,
+            color: selectedColor
+        }));
+    });
+}
+
 async function sendPixel() {
     if (selectedColor === -1) return;
 
-    let token = localStorage.getItem("token");
+    if (socketAuthenticated && socket.readyState === WebSocket.OPEN) {
+        let ack = await sendPixelOverSocket();
+        if (ack.error === undefined) {
+            localCooldown = ack.cooldown;
+            deselectColor();
+            updateCooldownDisplay();
+        } else if (ack.error.includes('token')) {
+            // the socket's token expired, draw over HTTP while it authenticates again
+            socketAuthenticated = false;
+            if (await refreshSession()) {
+                return sendPixel();
+            }
+            console.error(ack.error);
+        } else {
+            console.error(ack.error);
+        }
+        return;
+    }
 
     try {
-        const response = await fetch('/api/draw', {
+        const response = await authFetch('/api/draw', {
             method: 'POST',
             headers: {
-                'Content-Type': 'application/json',
-                'Authorization': `Bearer ${token}`
+                'Content-Type': 'application/json'
             },
             body: JSON.stringify({
                 x: oldPixel.x,
@@ -91,6 +185,7 @@ async function getGrid() {
                 'Accept': 'image/png'
             }
         });
+        canvasSeq = Number(pngResponse.headers.get('X-Sequence'));
         const blob = await pngResponse.blob();
         const imageUrl = URL.createObjectURL(blob);
 
@@ -101,11 +196,12 @@ async function getGrid() {
             ctx.drawImage(img, 0, 0);
             URL.revokeObjectURL(img.src);
 
-            const updatesResponse = await fetch('/api/updates');
+            const updatesResponse = await fetch(`/api/updates?since=${canvasSeq}`);
             const updates = await updatesResponse.json();
             updates.forEach(update => {
                 ctx.fillStyle = colors[update.color];
                 ctx.fillRect(update.x, update.y, 1, 1);
+                canvasSeq = Math.max(canvasSeq, update.seq);
             });
         };
     } catch (error) {
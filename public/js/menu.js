@@ -19,6 +19,18 @@ let signupEmailError = document.getElementById('signupEmailError');
 let signupUsernameError = document.getElementById('signupUsernameError');
 let signupPasswordError = document.getElementById('signupPasswordError');
 
+let forgotDiv = document.getElementById('forgot');
+let forgotButton = document.getElementById('forgotButton');
+let forgotLink = document.getElementById('forgotLink');
+let forgotLoginLink = document.getElementById('forgotLoginLink');
+let forgotEmail = document.getElementById('forgotEmail');
+let forgotEmailError = document.getElementById('forgotEmailError');
+
+let resetDiv = document.getElementById('reset');
+let resetButton = document.getElementById('resetButton');
+let resetPassword = document.getElementById('resetPassword');
+let resetPasswordError = document.getElementById('resetPasswordError');
+
 let profileDiv = document.getElementById('profile');
 let profileReloadButton = document.getElementById('profileReloadButton');
 let profilePlacedPixels = document.getElementById('profilePlacedPixels');
@@ -30,6 +42,7 @@ let profileUsernameError = document.getElementById('profileUsernameError');
 let profilePasswordError = document.getElementById('profilePasswordError');
 let profileCurrentPasswordError = document.getElementById('profileCurrentPasswordError');
 let logoutButton = document.getElementById('logoutButton');
+let logoutAllButton = document.getElementById('logoutAllButton');
 let saveButton = document.getElementById('saveButton');
 
 async function initMenu() {
@@ -37,15 +50,25 @@ async function initMenu() {
     toggleMenu();
     openLogin();
     await getProfile();
+
+    if (new URLSearchParams(window.location.search).has('reset')) {
+        openReset();
+        toggleMenu();
+    }
 }
 
 function setupListeners() {
     menuButton.addEventListener('click', toggleMenu);
     signupLink.addEventListener('click', openSignup);
     loginLink.addEventListener('click', openLogin);
+    forgotLink.addEventListener('click', openForgot);
+    forgotLoginLink.addEventListener('click', openLogin);
     loginButton.addEventListener('click', login);
+    forgotButton.addEventListener('click', forgotPassword);
+    resetButton.addEventListener('click', resetPasswordWithToken);
     signupButton.addEventListener('click', signup);
     logoutButton.addEventListener('click', logout);
+    logoutAllButton.addEventListener('click', logoutAll);
     saveButton.addEventListener('click', saveProfile);
 }
 
@@ -57,30 +80,134 @@ function openLogin() {
     loginDiv.style.display = "flex";
     signupDiv.style.display = "none";
     profileDiv.style.display = "none";
+    forgotDiv.style.display = "none";
+    resetDiv.style.display = "none";
 }
 
 function openSignup() {
     loginDiv.style.display = "none";
     signupDiv.style.display = "flex";
     profileDiv.style.display = "none";
+    forgotDiv.style.display = "none";
+    resetDiv.style.display = "none";
 }
 
 function openProfile() {
     loginDiv.style.display = "none";
     signupDiv.style.display = "none";
     profileDiv.style.display = "flex";
+    forgotDiv.style.display = "none";
+    resetDiv.style.display = "none";
+}
+
+function openForgot() {
+    loginDiv.style.display = "none";
+    signupDiv.style.display = "none";
+    profileDiv.style.display = "none";
+    forgotDiv.style.display = "flex";
+    resetDiv.style.display = "none";
 }
 
-function logout() {
+function openReset() {
+    loginDiv.style.display = "none";
+    signupDiv.style.display = "none";
+    profileDiv.style.display = "none";
+    forgotDiv.style.display = "none";
+    resetDiv.style.display = "flex";
+}
+
+function storeSession(session) {
+    localStorage.setItem('token', session.access_token);
+    localStorage.setItem('refreshToken', session.refresh_token);
+}
+
+function clearSession() {
     localStorage.removeItem('token');
+    localStorage.removeItem('refreshToken');
+}
+
+async function refreshSession() {
+    const refreshToken = localStorage.getItem('refreshToken');
+    if (refreshToken === null) {
+        return false;
+    }
+
+    const response = await fetch('/api/token/refresh', {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify({ refresh_token: refreshToken })
+    });
+
+    if (!response.ok) {
+        clearSession();
+        return false;
+    }
+
+    storeSession(await response.json());
+    authenticateSocket();
+    return true;
+}
+
+// fetch with the access token, refreshing the session once if it expired
+async function authFetch(url, options = {}) {
+    const send = () => fetch(url, {
+        ...options,
+        headers: {
+            ...options.headers,
+            'Authorization': `Bearer ${localStorage.getItem('token')}`
+        }
+    });
+
+    let response = await send();
+    if (response.status === 401 && await refreshSession()) {
+        response = await send();
+    }
+    return response;
+}
+
+async function logout() {
+    const refreshToken = localStorage.getItem('refreshToken');
+    clearSession();
     openLogin();
     toggleMenu();
     switchState("notConnected");
+
+    if (refreshToken !== null) {
+        try {
+            await fetch('/api/logout', {
+                method: 'POST',
+                headers: {
+                    'Content-Type': 'application/json'
+                },
+                body: JSON.stringify({ refresh_token: refreshToken })
+            });
+        } catch (error) {
+            console.error("Error:", error);
+        }
+    }
 }
 
-async function saveProfile() {
-    let token = localStorage.getItem("token");
+async function logoutAll() {
+    try {
+        const response = await authFetch('/api/logout/all', { method: 'POST' });
+        if (!response.ok) {
+            console.error("Error:", await response.text());
+            return;
+        }
+    } catch (error) {
+        console.error("Error:", error);
+        return;
+    }
 
+    clearSession();
+    openLogin();
+    toggleMenu();
+    switchState("notConnected");
+}
+
+async function saveProfile() {
     profileUsernameError.textContent = "";
 
     if(profileUsername.value.length < 3) {
@@ -113,11 +240,10 @@ async function saveProfile() {
     }
 
     try {
-        const response = await fetch('/api/profile/edit', {
+        const response = await authFetch('/api/profile/edit', {
             method: 'POST',
             headers: {
-                'Content-Type': 'application/json',
-                'Authorization': `Bearer ${token}`
+                'Content-Type': 'application/json'
             },
             body: JSON.stringify({
                 username: profileUsername.value.toLowerCase(),
@@ -127,6 +253,10 @@ async function saveProfile() {
         });
 
         if (response.ok) {
+            if (profilePassword.value) {
+                storeSession(await response.json());
+                authenticateSocket();
+            }
             getProfile();
             toggleMenu();
             getLeaderboard();
@@ -164,19 +294,17 @@ async function getProfile() {
     }
 
     try {
-        let profileResponse = await fetch('/api/profile/me', {
+        let profileResponse = await authFetch('/api/profile/me', {
             method: 'GET',
             headers: {
-                'Content-Type': 'application/json',
-                'Authorization': `Bearer ${token}`
+                'Content-Type': 'application/json'
             }
         });
 
-        let countResponse = await fetch('/api/users/count', {
+        let countResponse = await authFetch('/api/users/count', {
             method: 'GET',
             headers: {
-                'Content-Type': 'application/json',
-                'Authorization': `Bearer ${token}`
+                'Content-Type': 'application/json'
             }
         });
 
@@ -198,7 +326,7 @@ async function getProfile() {
             }
             openProfile();
         } else {
-            localStorage.removeItem('token');
+            clearSession();
             switchState("notConnected");
             openLogin();
             console.error("Error:", await profileResponse.text());
@@ -234,8 +362,8 @@ async function login() {
         });
 
         if (response.ok) {
-            let token = await response.text();
-            localStorage.setItem('token', token);
+            storeSession(await response.json());
+            authenticateSocket();
             await getProfile();
             openProfile();
             toggleMenu();
@@ -290,10 +418,84 @@ async function signup() {
             loginUsernameError.textContent = "Verification email sent.";
             loginPasswordError.textContent = "Please check your inbox.";
         } else {
+            let message = await signupResponse.text();
+            if (signupResponse.status === 400 && message.toLowerCase().includes("email")) {
+                signupEmailError.textContent = message;
+                return;
+            }
             signupEmailError.textContent = "Something went wrong.";
             signupUsernameError.textContent = "Something went wrong.";
             signupPasswordError.textContent = "Something went wrong.";
-            console.error("Error:", await signupResponse.text());
+            console.error("Error:", message);
+        }
+    } catch (error) {
+        console.error("Error:", error);
+    }
+}
+
+async function forgotPassword() {
+    try {
+        forgotEmail.value = forgotEmail.value.trim();
+        if(forgotEmail.value === "") {
+            forgotEmailError.textContent = "Please enter your email address.";
+            return;
+        }
+
+        const response = await fetch('/api/password/forgot', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            body: JSON.stringify({
+                email: forgotEmail.value.toLowerCase()
+            })
+        });
+
+        switch(response.status) {
+            case 200:
+                forgotEmailError.textContent = "If this email has an account, a reset link was sent to it.";
+                break;
+            case 429:
+                forgotEmailError.textContent = "Too many requests, please try again later.";
+                break;
+            default:
+                forgotEmailError.textContent = "Something went wrong.";
+                console.error("Error:", await response.text());
+        }
+    } catch (error) {
+        console.error("Error:", error);
+    }
+}
+
+async function resetPasswordWithToken() {
+    if(resetPassword.value.length < 8) {
+        resetPasswordError.textContent = "Please enter at least 8 characters.";
+        return;
+    } else if(resetPassword.value.length > 128) {
+        resetPasswordError.textContent = "Please enter at most 128 characters.";
+        return;
+    }
+    resetPasswordError.textContent = "";
+
+    try {
+        const response = await fetch('/api/password/reset', {
+            method: 'POST',
+            headers: {
+                'Content-Type': 'application/json'
+            },
+            body: JSON.stringify({
+                token: new URLSearchParams(window.location.search).get('reset'),
+                password: resetPassword.value
+            })
+        });
+
+        if (response.ok) {
+            window.history.replaceState(null, '', window.location.pathname);
+            openLogin();
+            loginPasswordError.textContent = "Password changed, you can log in.";
+        } else {
+            resetPasswordError.textContent = "This link is invalid or has expired.";
+            console.error("Error:", await response.text());
         }
     } catch (error) {
         console.error("Error:", error);
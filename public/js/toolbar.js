@@ -16,7 +16,7 @@ function deselectColor() {
 
 async function initPalette() {
     try {
-        let response = await fetch("/misc/colors.json",
+        let response = await fetch("/api/palette",
             {
                 method: 'GET',
                 headers: {
@@ -24,28 +24,41 @@ async function initPalette() {
                 }
             });
         if (response.ok) {
-            colors = await response.json();
-            colors = colors.colors;
+            renderPalette(await response.json());
         } else {
             console.error(await response.text());
         }
     } catch (error) {
         console.error("Error:", error);
     }
+}
+
+function renderPalette(palette) {
+    colors = palette.colors;
+    if (palette.retired.includes(selectedColor)) {
+        deselectColor();
+    }
+    colorPicker.replaceChildren();
 
-    colors.forEach((color) => {
+    colors.forEach((color, index) => {
+        if (palette.retired.includes(index)) {
+            return;
+        }
         const colorBlock = document.createElement('button');
-        colorBlock.className = 'color-block';
+        colorBlock.className = index === selectedColor ? 'color-block selected-color-block' : 'color-block';
         colorBlock.style.backgroundColor = color;
         colorBlock.setAttribute('aria-label', `Select color ${color}`);
         colorBlock.addEventListener('click', () => {
             deselectColor();
-            selectedColor = colors.indexOf(color);
+            selectedColor = index;
             colorBlock.className = 'color-block selected-color-block';
             oldSelectedColorBlock = colorBlock;
             selectedPixel.style.backgroundColor = color;
             showDrawButton();
         });
+        if (index === selectedColor) {
+            oldSelectedColorBlock = colorBlock;
+        }
         colorPicker.appendChild(colorBlock);
     });
 }
@@ -93,6 +106,29 @@ function updateCooldownDisplay() {
     setTimeout(updateCooldownDisplay, 1000);
 }
 
+async function resendVerification() {
+    try {
+        const response = await authFetch('/api/verify/resend', { method: 'POST' });
+
+        switch(response.status) {
+            case 200:
+                notVerified.textContent = "Verification email sent";
+                break;
+            case 409:
+                await getProfile();
+                break;
+            case 429:
+                notVerified.textContent = "Too many emails, try again later";
+                break;
+            default:
+                console.error("Error:", await response.text());
+        }
+    } catch (error) {
+        console.error("Error:", error);
+    }
+}
+
 document.addEventListener('DOMContentLoaded', () => {
     notConnected.addEventListener('click', toggleMenu);
+    notVerified.addEventListener('click', resendVerification);
 });
\ No newline at end of file
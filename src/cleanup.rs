@@ -0,0 +1,63 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix::{Actor, ActorFutureExt, AsyncContext, Context, WrapFuture};
+use actix_web::web;
+use chrono::Utc;
+
+use crate::database::Database;
+use crate::models::appstate::AppState;
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes the accounts left unverified longer than `UNVERIFIED_ACCOUNT_LIFETIME_SEC` every
+/// hour, freeing their usernames and emails
+pub struct AccountCleanup {
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+}
+
+impl AccountCleanup {
+    pub fn new(appstate: web::Data<RwLock<AppState>>, database: web::Data<Database>) -> Self {
+        Self { appstate, database }
+    }
+
+    fn start_cleanup(&mut self, ctx: &mut Context<Self>) {
+        let lifetime = match self.appstate.read() {
+            Ok(appstate) => appstate.unverified_lifetime(),
+            Err(_) => return,
+        };
+        if lifetime <= 0 {
+            return;
+        }
+
+        let database = self.database.clone();
+        let created_before = Utc::now().timestamp() - lifetime;
+        ctx.spawn(
+            web::block(move || database.delete_unverified_users(created_before))
+                .into_actor(self)
+                .map(|result, act, _| match result {
+                    Ok(Ok(user_ids)) if !user_ids.is_empty() => {
+                        if let Ok(mut appstate) = act.appstate.write() {
+                            for user_id in &user_ids {
+                                appstate.remove_user(*user_id);
+                            }
+                        }
+                        println!("Deleted {} unverified accounts", user_ids.len());
+                    }
+                    Ok(Ok(_)) => (),
+                    Ok(Err(err)) => eprintln!("Error deleting unverified accounts: {}", err),
+                    Err(err) => eprintln!("Error running account cleanup: {}", err),
+                }),
+        );
+    }
+}
+
+impl Actor for AccountCleanup {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.start_cleanup(ctx);
+        ctx.run_interval(CLEANUP_INTERVAL, |act, ctx| act.start_cleanup(ctx));
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix::{Actor, Addr, AsyncContext, Context};
+use actix_web::web;
+use chrono::Utc;
+
+use crate::flusher::{Flush, Flusher};
+use crate::models::appstate::AppState;
+
+/// Checks the event schedule of every canvas each second, so clients get state changes and
+/// countdowns on time, and asks for a flush when an event closes to snapshot it
+pub struct EventClock {
+    appstate: web::Data<RwLock<AppState>>,
+    flusher: Addr<Flusher>,
+}
+
+impl EventClock {
+    pub fn new(appstate: web::Data<RwLock<AppState>>, flusher: Addr<Flusher>) -> Self {
+        Self { appstate, flusher }
+    }
+
+    fn tick(&mut self) {
+        let now = Utc::now().timestamp();
+        match self.appstate.read() {
+            Ok(appstate) if appstate.events_due(now) => (),
+            _ => return,
+        }
+
+        let closed = match self.appstate.write() {
+            Ok(mut appstate) => appstate.update_events(now),
+            Err(_) => return,
+        };
+
+        if closed {
+            self.flusher.do_send(Flush);
+        }
+    }
+}
+
+impl Actor for EventClock {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(Duration::from_secs(1), |act, _| act.tick());
+    }
+}
@@ -1,11 +1,15 @@
 use std::collections::HashMap;
 use std::env;
 
+use chrono::Utc;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_derive::Serialize;
 use thiserror::Error;
 
-use crate::models::user::User;
+use crate::models::appstate::VERIFICATION_CODE_LIFETIME_SEC;
+use crate::models::event::EventSchedule;
+use crate::models::user::{Role, User};
 use crate::routes::user::ProfileEdit;
 
 #[derive(Error, Debug)]
@@ -20,12 +24,96 @@ pub enum DatabaseError {
     NoSuchRow,
 }
 
+pub const DEFAULT_COLOR: u8 = 31;
+
+/// Color of pixels never drawn on, the last one for palettes shorter than the default
+pub fn blank_color(palette_len: usize) -> u8 {
+    DEFAULT_COLOR.min(palette_len.saturating_sub(1) as u8)
+}
+
+#[derive(Clone)]
 pub struct DatabaseUpdate {
+    pub seq: u64,
+    pub canvas_id: u32,
     pub x: usize,
     pub y: usize,
     pub color: u8,
     pub user_id: u16,
     pub timestamp: i64,
+    /// Placement put back by a rollback, not counted in the score of its user
+    pub restored: bool,
+}
+
+/// Pixel to put back by a rollback with its previous color and user, `None` if it was never
+/// drawn on before
+pub struct RollbackPixel {
+    pub x: usize,
+    pub y: usize,
+    pub previous: Option<(u8, u16)>,
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool, DatabaseError> {
+    let mut statement = connection.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(1)?;
+        if name == column {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn has_unique_placement(connection: &Connection) -> Result<bool, DatabaseError> {
+    let sql: String = connection.query_row(
+        "SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'pixels'",
+        [],
+        |row| row.get(0),
+    )?;
+
+    Ok(sql.contains("UNIQUE"))
+}
+
+pub struct CanvasInfo {
+    pub canvas_id: u32,
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub cooldown: Option<u16>,
+    /// JSON array of hex colors, the default palette is used when `None`
+    pub palette: Option<String>,
+    /// JSON array of the palette indices that can no longer be drawn with
+    pub retired_colors: Option<String>,
+    /// Palette index of pixels never drawn on, `None` until the canvas is first loaded
+    pub blank_color: Option<u8>,
+    /// `closes_at` of the event whose final snapshot was taken
+    pub close_snapshot: Option<i64>,
+    pub schedule: EventSchedule,
+}
+
+pub struct RegionInfo {
+    pub region_id: u32,
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub mask: Option<Vec<u8>>,
+}
+
+/// Moderation action, with the usernames of its actor and target when they still exist
+#[derive(Serialize)]
+pub struct ModerationEntry {
+    pub log_id: u64,
+    pub actor: u16,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target_user: Option<u16>,
+    pub target_name: Option<String>,
+    pub canvas: Option<String>,
+    pub params: serde_json::Value,
+    pub timestamp: i64,
 }
 
 pub struct Database {
@@ -52,25 +140,505 @@ impl Database {
                 password TEXT NOT NULL,
                 email TEXT NOT NULL UNIQUE,
                 verification_code TEXT NOT NULL,
-                verified INTEGER NOT NULL DEFAULT 0
+                verified INTEGER NOT NULL DEFAULT 0,
+                role TEXT NOT NULL DEFAULT 'user',
+                banned INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER,
+                verification_expires_at INTEGER,
+                token_version INTEGER NOT NULL DEFAULT 0
             )",
             [],
         )?;
 
+        if !has_column(&connection, "users", "role")? {
+            connection.execute_batch(
+                "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+                ALTER TABLE users ADD COLUMN banned INTEGER NOT NULL DEFAULT 0;",
+            )?;
+        }
+
+        // Accounts from before verification codes expired get a fresh code lifetime
+        if !has_column(&connection, "users", "created_at")? {
+            let now = Utc::now().timestamp();
+            connection.execute_batch(
+                "ALTER TABLE users ADD COLUMN created_at INTEGER;
+                ALTER TABLE users ADD COLUMN verification_expires_at INTEGER;",
+            )?;
+            connection.execute(
+                "UPDATE users SET created_at = ?1,
+                verification_expires_at = CASE WHEN verified = 0 THEN ?2 END",
+                params![now, now + VERIFICATION_CODE_LIFETIME_SEC],
+            )?;
+        }
+
+        // Tokens from before refresh tokens lasted a week, revoke them with the new version
+        if !has_column(&connection, "users", "token_version")? {
+            connection.execute_batch(
+                "ALTER TABLE users ADD COLUMN token_version INTEGER NOT NULL DEFAULT 0;
+                UPDATE users SET token_version = 1;",
+            )?;
+        }
+
         connection.execute(
             "CREATE TABLE IF NOT EXISTS pixels (
                 pixel_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                canvas_id INTEGER NOT NULL DEFAULT 1,
                 x INTEGER NOT NULL,
                 y INTEGER NOT NULL,
                 color INTEGER NOT NULL,
                 user INTEGER NOT NULL,
                 timestamp INTEGER NOT NULL,
-                UNIQUE(x, y, timestamp),
+                restored INTEGER NOT NULL DEFAULT 0,
+                rolled_back INTEGER NOT NULL DEFAULT 0,
                 FOREIGN KEY(user) REFERENCES users(user_id)
             )",
             [],
         )?;
 
+        // Older tables had no canvas_id, and a unique (x, y, timestamp) that dropped the second
+        // placement on a pixel within the same second
+        let canvas_column = has_column(&connection, "pixels", "canvas_id")?;
+        if !canvas_column || has_unique_placement(&connection)? {
+            let columns = if canvas_column {
+                "pixel_id, canvas_id, x, y, color, user, timestamp"
+            } else {
+                "pixel_id, x, y, color, user, timestamp"
+            };
+            connection.execute_batch(&format!(
+                "BEGIN;
+                CREATE TABLE pixels_rebuild (
+                    pixel_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    canvas_id INTEGER NOT NULL DEFAULT 1,
+                    x INTEGER NOT NULL,
+                    y INTEGER NOT NULL,
+                    color INTEGER NOT NULL,
+                    user INTEGER NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    restored INTEGER NOT NULL DEFAULT 0,
+                    rolled_back INTEGER NOT NULL DEFAULT 0,
+                    FOREIGN KEY(user) REFERENCES users(user_id)
+                );
+                INSERT INTO pixels_rebuild ({columns})
+                SELECT {columns} FROM pixels;
+                DROP TABLE pixels;
+                ALTER TABLE pixels_rebuild RENAME TO pixels;
+                COMMIT;",
+                columns = columns
+            ))?;
+        }
+
+        if !has_column(&connection, "pixels", "rolled_back")? {
+            connection.execute(
+                "ALTER TABLE pixels ADD COLUMN rolled_back INTEGER NOT NULL DEFAULT 0",
+                [],
+            )?;
+        }
+
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS pixels_position ON pixels(canvas_id, x, y)",
+            [],
+        )?;
+        connection.execute("CREATE INDEX IF NOT EXISTS pixels_user ON pixels(user)", [])?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS canvas (
+                canvas_id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL DEFAULT 'main',
+                width INTEGER NOT NULL,
+                height INTEGER NOT NULL,
+                cooldown INTEGER,
+                palette TEXT,
+                opens_at INTEGER,
+                closes_at INTEGER,
+                frozen INTEGER NOT NULL DEFAULT 0,
+                retired_colors TEXT,
+                blank_color INTEGER,
+                close_snapshot INTEGER
+            )",
+            [],
+        )?;
+
+        if !has_column(&connection, "canvas", "name")? {
+            connection.execute_batch(
+                "ALTER TABLE canvas ADD COLUMN name TEXT NOT NULL DEFAULT 'main';
+                ALTER TABLE canvas ADD COLUMN cooldown INTEGER;
+                ALTER TABLE canvas ADD COLUMN palette TEXT;",
+            )?;
+        }
+
+        if !has_column(&connection, "canvas", "opens_at")? {
+            connection.execute_batch(
+                "ALTER TABLE canvas ADD COLUMN opens_at INTEGER;
+                ALTER TABLE canvas ADD COLUMN closes_at INTEGER;
+                ALTER TABLE canvas ADD COLUMN frozen INTEGER NOT NULL DEFAULT 0;",
+            )?;
+        }
+
+        if !has_column(&connection, "canvas", "retired_colors")? {
+            connection.execute("ALTER TABLE canvas ADD COLUMN retired_colors TEXT", [])?;
+        }
+
+        if !has_column(&connection, "canvas", "blank_color")? {
+            connection.execute("ALTER TABLE canvas ADD COLUMN blank_color INTEGER", [])?;
+        }
+
+        if !has_column(&connection, "canvas", "close_snapshot")? {
+            connection.execute("ALTER TABLE canvas ADD COLUMN close_snapshot INTEGER", [])?;
+        }
+
+        connection.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS canvas_name ON canvas(name)",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS protected_region (
+                region_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                canvas_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                x INTEGER NOT NULL,
+                y INTEGER NOT NULL,
+                width INTEGER NOT NULL,
+                height INTEGER NOT NULL,
+                mask BLOB
+            )",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS password_reset (
+                token TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                used INTEGER NOT NULL DEFAULT 0,
+                FOREIGN KEY(user_id) REFERENCES users(user_id)
+            )",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS refresh_token (
+                token TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                FOREIGN KEY(user_id) REFERENCES users(user_id)
+            )",
+            [],
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS moderation_log (
+                log_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                target_user INTEGER,
+                canvas TEXT,
+                params TEXT NOT NULL,
+                timestamp INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn log_moderation(
+        &self,
+        actor: u16,
+        action: &str,
+        target_user: Option<u16>,
+        canvas: Option<&str>,
+        params: &serde_json::Value,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+        connection.execute(
+            "INSERT INTO moderation_log (actor, action, target_user, canvas, params, timestamp)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                actor,
+                action,
+                target_user,
+                canvas,
+                params.to_string(),
+                Utc::now().timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Moderation actions from the newest, optionally only those made by `actor` or on
+    /// `target_user`, with the total number of matching actions
+    pub fn get_moderation_log(
+        &self,
+        actor: Option<u16>,
+        target_user: Option<u16>,
+        offset: u64,
+        limit: u64,
+    ) -> Result<(Vec<ModerationEntry>, u64), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let total: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM moderation_log
+            WHERE (?1 IS NULL OR actor = ?1) AND (?2 IS NULL OR target_user = ?2)",
+            params![actor, target_user],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = connection.prepare(
+            "SELECT log_id, actor, actors.username, action, target_user, targets.username, canvas, params, timestamp
+            FROM moderation_log
+            LEFT JOIN users actors ON actors.user_id = actor
+            LEFT JOIN users targets ON targets.user_id = target_user
+            WHERE (?1 IS NULL OR actor = ?1) AND (?2 IS NULL OR target_user = ?2)
+            ORDER BY log_id DESC
+            LIMIT ?3 OFFSET ?4",
+        )?;
+        let mut rows = statement.query(params![
+            actor,
+            target_user,
+            limit as i64,
+            offset.min(i64::MAX as u64) as i64
+        ])?;
+
+        let mut entries = Vec::new();
+        while let Some(row) = rows.next()? {
+            let log_id: i64 = row.get(0)?;
+            let actor: i64 = row.get(1)?;
+            let target_user: Option<i64> = row.get(4)?;
+            let params: String = row.get(7)?;
+            entries.push(ModerationEntry {
+                log_id: log_id as u64,
+                actor: actor as u16,
+                actor_name: row.get(2)?,
+                action: row.get(3)?,
+                target_user: target_user.map(|user| user as u16),
+                target_name: row.get(5)?,
+                canvas: row.get(6)?,
+                params: serde_json::from_str(&params).unwrap_or_default(),
+                timestamp: row.get(8)?,
+            });
+        }
+
+        Ok((entries, total as u64))
+    }
+
+    pub fn get_canvases(&self) -> Result<Vec<CanvasInfo>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let mut statement = connection.prepare(
+            "SELECT canvas_id, name, width, height, cooldown, palette, opens_at, closes_at, frozen,
+            retired_colors, blank_color, close_snapshot
+            FROM canvas
+            ORDER BY canvas_id",
+        )?;
+        let mut rows = statement.query([])?;
+
+        let mut canvases = Vec::new();
+        while let Some(row) = rows.next()? {
+            let canvas_id: i64 = row.get(0)?;
+            let width: i64 = row.get(2)?;
+            let height: i64 = row.get(3)?;
+            let cooldown: Option<i64> = row.get(4)?;
+            let frozen: i64 = row.get(8)?;
+            let blank_color: Option<i64> = row.get(10)?;
+            canvases.push(CanvasInfo {
+                canvas_id: canvas_id as u32,
+                name: row.get(1)?,
+                width: width as usize,
+                height: height as usize,
+                cooldown: cooldown.map(|cooldown| cooldown as u16),
+                palette: row.get(5)?,
+                retired_colors: row.get(9)?,
+                blank_color: blank_color.map(|color| color as u8),
+                close_snapshot: row.get(11)?,
+                schedule: EventSchedule {
+                    opens_at: row.get(6)?,
+                    closes_at: row.get(7)?,
+                    frozen: frozen == 1,
+                },
+            });
+        }
+
+        Ok(canvases)
+    }
+
+    pub fn create_canvas(
+        &self,
+        name: &str,
+        width: usize,
+        height: usize,
+        cooldown: Option<u16>,
+        palette: Option<&str>,
+    ) -> Result<u32, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "INSERT INTO canvas (name, width, height, cooldown, palette) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![name, width as i64, height as i64, cooldown, palette],
+        )?;
+
+        Ok(connection.last_insert_rowid() as u32)
+    }
+
+    pub fn save_canvas_palette(
+        &self,
+        canvas_id: u32,
+        palette: &str,
+        retired_colors: &str,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE canvas SET palette = ?1, retired_colors = ?2 WHERE canvas_id = ?3",
+            params![palette, retired_colors, canvas_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn save_canvas_blank_color(&self, canvas_id: u32, color: u8) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE canvas SET blank_color = ?1 WHERE canvas_id = ?2",
+            params![color, canvas_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records that the final snapshot of the event closing at `closes_at` was taken
+    pub fn save_close_snapshot(&self, canvas_id: u32, closes_at: i64) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE canvas SET close_snapshot = ?1 WHERE canvas_id = ?2",
+            params![closes_at, canvas_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn save_canvas_schedule(
+        &self,
+        canvas_id: u32,
+        schedule: &EventSchedule,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE canvas SET opens_at = ?1, closes_at = ?2, frozen = ?3 WHERE canvas_id = ?4",
+            params![
+                schedule.opens_at,
+                schedule.closes_at,
+                schedule.frozen,
+                canvas_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_regions(&self, canvas_id: u32) -> Result<Vec<RegionInfo>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let mut statement = connection.prepare(
+            "SELECT region_id, name, x, y, width, height, mask
+            FROM protected_region
+            WHERE canvas_id = ?1
+            ORDER BY region_id",
+        )?;
+        let mut rows = statement.query(params![canvas_id])?;
+
+        let mut regions = Vec::new();
+        while let Some(row) = rows.next()? {
+            let region_id: i64 = row.get(0)?;
+            let x: i64 = row.get(2)?;
+            let y: i64 = row.get(3)?;
+            let width: i64 = row.get(4)?;
+            let height: i64 = row.get(5)?;
+            regions.push(RegionInfo {
+                region_id: region_id as u32,
+                name: row.get(1)?,
+                x: x as usize,
+                y: y as usize,
+                width: width as usize,
+                height: height as usize,
+                mask: row.get(6)?,
+            });
+        }
+
+        Ok(regions)
+    }
+
+    pub fn create_region(
+        &self,
+        canvas_id: u32,
+        name: &str,
+        (x, y, width, height): (usize, usize, usize, usize),
+        mask: Option<&[u8]>,
+    ) -> Result<u32, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "INSERT INTO protected_region (canvas_id, name, x, y, width, height, mask)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                canvas_id,
+                name,
+                x as i64,
+                y as i64,
+                width as i64,
+                height as i64,
+                mask
+            ],
+        )?;
+
+        Ok(connection.last_insert_rowid() as u32)
+    }
+
+    pub fn delete_region(&self, region_id: u32) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let deleted = connection.execute(
+            "DELETE FROM protected_region WHERE region_id = ?1",
+            params![region_id],
+        )?;
+        if deleted == 0 {
+            return Err(DatabaseError::NoSuchRow);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_region_mask(&self, region_id: u32) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let mask: Option<Option<Vec<u8>>> = connection
+            .query_row(
+                "SELECT mask FROM protected_region WHERE region_id = ?1",
+                params![region_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        mask.ok_or(DatabaseError::NoSuchRow)
+    }
+
+    pub fn save_canvas_size(
+        &self,
+        canvas_id: u32,
+        width: usize,
+        height: usize,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE canvas SET width = ?1, height = ?2 WHERE canvas_id = ?3",
+            params![width as i64, height as i64, canvas_id],
+        )?;
+
         Ok(())
     }
 
@@ -80,34 +648,319 @@ impl Database {
         password: &str,
         email: &str,
         verification_code: &str,
+        verification_expires_at: i64,
     ) -> Result<u16, DatabaseError> {
         let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
         let connection = self.pool.get()?;
 
         connection.execute(
-            "INSERT INTO users (username, password, email, verification_code) VALUES (?1, ?2, ?3, ?4)",
-            params![username, &hash, email, verification_code],
+            "INSERT INTO users (username, password, email, verification_code, created_at, verification_expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                username,
+                &hash,
+                email,
+                verification_code,
+                Utc::now().timestamp(),
+                verification_expires_at
+            ],
         )?;
 
         Ok(connection.last_insert_rowid() as u16)
     }
 
-    pub fn verify(&self, verification_code: &str) -> Result<u16, DatabaseError> {
+    /// Verifies the user of an unexpired verification code, `None` if there is none
+    pub fn verify(&self, verification_code: &str) -> Result<Option<u16>, DatabaseError> {
         let connection = self.pool.get()?;
 
-        let mut statement =
-            connection.prepare("SELECT user_id FROM users WHERE verification_code = ?1")?;
-        let mut rows = statement.query(params![verification_code])?;
+        let mut statement = connection.prepare(
+            "SELECT user_id FROM users WHERE verification_code = ?1 AND verification_expires_at > ?2",
+        )?;
+        let mut rows = statement.query(params![verification_code, Utc::now().timestamp()])?;
         if let Some(row) = rows.next()? {
             let id: i64 = row.get(0)?;
             connection.execute(
-                "UPDATE users SET verified = 1 WHERE user_id = ?1",
+                "UPDATE users SET verified = 1, verification_expires_at = NULL WHERE user_id = ?1",
                 params![id],
             )?;
-            Ok(id as u16)
+            Ok(Some(id as u16))
         } else {
-            Err(DatabaseError::NoSuchRow)
+            Ok(None)
+        }
+    }
+
+    /// Email of a user still waiting for verification, `None` once verified or when a moderator
+    /// took over its verification
+    pub fn get_pending_email(&self, user_id: u16) -> Result<Option<String>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        Ok(connection
+            .query_row(
+                "SELECT email FROM users
+                WHERE user_id = ?1 AND verified = 0 AND verification_expires_at IS NOT NULL",
+                params![user_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Replaces the verification code of a user, the previous one can no longer be used
+    pub fn renew_verification_code(
+        &self,
+        user_id: u16,
+        verification_code: &str,
+        verification_expires_at: i64,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE users SET verification_code = ?1, verification_expires_at = ?2 WHERE user_id = ?3",
+            params![verification_code, verification_expires_at, user_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes the accounts still waiting for verification that signed up before `created_before`
+    /// and never placed a pixel, returning their ids
+    pub fn delete_unverified_users(&self, created_before: i64) -> Result<Vec<u16>, DatabaseError> {
+        let mut connection = self.pool.get()?;
+
+        let tx = connection.transaction()?;
+        let user_ids = {
+            let mut statement = tx.prepare(
+                "SELECT user_id FROM users
+                WHERE verified = 0 AND verification_expires_at IS NOT NULL AND created_at <= ?1
+                AND NOT EXISTS (SELECT 1 FROM pixels WHERE pixels.user = users.user_id)",
+            )?;
+            let rows = statement.query_map(params![created_before], |row| row.get::<_, i64>(0))?;
+            rows.map(|id| id.map(|id| id as u16))
+                .collect::<Result<Vec<u16>, _>>()?
+        };
+
+        for user_id in &user_ids {
+            tx.execute(
+                "DELETE FROM password_reset WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            tx.execute(
+                "DELETE FROM refresh_token WHERE user_id = ?1",
+                params![user_id],
+            )?;
+            tx.execute("DELETE FROM users WHERE user_id = ?1", params![user_id])?;
+        }
+        tx.commit()?;
+
+        Ok(user_ids)
+    }
+
+    /// Saves a refresh token, dropping the expired ones
+    pub fn create_refresh_token(
+        &self,
+        user_id: u16,
+        token: &str,
+        expires_at: i64,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "DELETE FROM refresh_token WHERE expires_at <= ?1",
+            params![Utc::now().timestamp()],
+        )?;
+        connection.execute(
+            "INSERT INTO refresh_token (token, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            params![token, user_id, expires_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// Deletes a refresh token, returning its user if it had not expired
+    pub fn take_refresh_token(&self, token: &str) -> Result<Option<u16>, DatabaseError> {
+        let mut connection = self.pool.get()?;
+
+        let tx = connection.transaction()?;
+        let user_id: Option<(i64, i64)> = tx
+            .query_row(
+                "SELECT user_id, expires_at FROM refresh_token WHERE token = ?1",
+                params![token],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let deleted = tx.execute("DELETE FROM refresh_token WHERE token = ?1", params![token])?;
+        tx.commit()?;
+
+        Ok(user_id
+            .filter(|_| deleted == 1)
+            .filter(|(_, expires_at)| *expires_at > Utc::now().timestamp())
+            .map(|(user_id, _)| user_id as u16))
+    }
+
+    /// Deletes every refresh token of a user and bumps their token version, so that their
+    /// access tokens stop working too. Returns the new version
+    pub fn revoke_tokens(&self, user_id: u16) -> Result<u32, DatabaseError> {
+        let mut connection = self.pool.get()?;
+
+        let tx = connection.transaction()?;
+        tx.execute(
+            "DELETE FROM refresh_token WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        tx.execute(
+            "UPDATE users SET token_version = token_version + 1 WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        let version: i64 = tx.query_row(
+            "SELECT token_version FROM users WHERE user_id = ?1",
+            params![user_id],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+
+        Ok(version as u32)
+    }
+
+    /// Id and username of the user with `email`, whatever its case
+    pub fn get_user_by_email(&self, email: &str) -> Result<Option<(u16, String)>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        Ok(connection
+            .query_row(
+                "SELECT user_id, username FROM users WHERE lower(email) = lower(?1)",
+                params![email],
+                |row| Ok((row.get::<_, i64>(0)? as u16, row.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    /// Saves a password reset token, dropping the expired ones
+    pub fn create_password_reset(
+        &self,
+        user_id: u16,
+        token: &str,
+        expires_at: i64,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "DELETE FROM password_reset WHERE expires_at <= ?1",
+            params![Utc::now().timestamp()],
+        )?;
+        connection.execute(
+            "INSERT INTO password_reset (token, user_id, expires_at) VALUES (?1, ?2, ?3)",
+            params![token, user_id, expires_at],
+        )?;
+
+        Ok(())
+    }
+
+    /// Sets the password of the user of an unused and unexpired reset token, then invalidates
+    /// every reset token of that user. Returns the user, `None` if the token can't be used
+    pub fn reset_password(
+        &self,
+        token: &str,
+        password: &str,
+    ) -> Result<Option<u16>, DatabaseError> {
+        let now = Utc::now().timestamp();
+        let mut connection = self.pool.get()?;
+
+        let user_id: Option<i64> = connection
+            .query_row(
+                "SELECT user_id FROM password_reset WHERE token = ?1 AND used = 0 AND expires_at > ?2",
+                params![token, now],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        let hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)?;
+
+        let tx = connection.transaction()?;
+        // The token may have been used while hashing
+        let claimed = tx.execute(
+            "UPDATE password_reset SET used = 1 WHERE token = ?1 AND used = 0 AND expires_at > ?2",
+            params![token, now],
+        )?;
+        if claimed == 0 {
+            return Ok(None);
+        }
+        tx.execute(
+            "UPDATE password_reset SET used = 1 WHERE user_id = ?1",
+            params![user_id],
+        )?;
+        tx.execute(
+            "UPDATE users SET password = ?1 WHERE user_id = ?2",
+            params![hash, user_id],
+        )?;
+        tx.commit()?;
+
+        Ok(Some(user_id as u16))
+    }
+
+    pub fn get_user_id(&self, username: &str) -> Result<Option<u16>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let mut statement = connection.prepare("SELECT user_id FROM users WHERE username = ?1")?;
+        let mut rows = statement.query(params![username])?;
+
+        match rows.next()? {
+            Some(row) => {
+                let user_id: i64 = row.get(0)?;
+                Ok(Some(user_id as u16))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_role(&self, user_id: u16, role: Role) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE users SET role = ?1 WHERE user_id = ?2",
+            params![role.as_str(), user_id],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_roles(&self) -> Result<HashMap<u16, Role>, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let mut statement = connection.prepare("SELECT user_id, role FROM users")?;
+        let mut rows = statement.query([])?;
+        let mut roles = HashMap::new();
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let role: String = row.get(1)?;
+            roles.insert(id as u16, role.parse().unwrap_or_default());
         }
+
+        Ok(roles)
+    }
+
+    pub fn set_banned(&self, user_id: u16, banned: bool) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE users SET banned = ?1 WHERE user_id = ?2",
+            params![banned, user_id],
+        )?;
+
+        Ok(())
+    }
+
+    /// Verifies or unverifies a user, which then no longer waits for a verification code
+    pub fn set_verified(&self, user_id: u16, verified: bool) -> Result<(), DatabaseError> {
+        let connection = self.pool.get()?;
+
+        connection.execute(
+            "UPDATE users SET verified = ?1, verification_expires_at = NULL WHERE user_id = ?2",
+            params![verified, user_id],
+        )?;
+
+        Ok(())
     }
 
     pub fn login(&self, username: &str, password: &str) -> Result<Option<u16>, DatabaseError> {
@@ -152,30 +1005,47 @@ impl Database {
 
     pub fn get_pixels(
         &self,
+        canvas_id: u32,
+        width: usize,
+        height: usize,
+        blank: u8,
+    ) -> Result<(Vec<u8>, Vec<u16>), DatabaseError> {
+        self.get_pixels_at(canvas_id, width, height, blank, i64::MAX)
+    }
+
+    pub fn get_pixels_at(
+        &self,
+        canvas_id: u32,
         width: usize,
         height: usize,
+        blank: u8,
+        timestamp: i64,
     ) -> Result<(Vec<u8>, Vec<u16>), DatabaseError> {
         let connection = self.pool.get()?;
         let mut statement = connection.prepare(
             "SELECT pixels.x, pixels.y, pixels.user, pixels.color
             FROM pixels
             JOIN (
-                SELECT x, y, MAX(timestamp) as max_timestamp
+                SELECT MAX(pixel_id) as max_pixel_id
                 FROM pixels
+                WHERE canvas_id = ?1 AND timestamp <= ?2
                 GROUP BY x, y
             ) pixel
-            ON pixels.x = pixel.x AND pixels.y = pixel.y AND pixels.timestamp = pixel.max_timestamp
+            ON pixels.pixel_id = pixel.max_pixel_id
             ORDER BY pixels.x, pixels.y;",
         )?;
 
-        let mut rows = statement.query([])?;
-        let mut pixels_color = vec![31; width * height];
+        let mut rows = statement.query(params![canvas_id, timestamp])?;
+        let mut pixels_color = vec![blank; width * height];
         let mut pixels_user = vec![0; width * height];
         while let Some(row) = rows.next()? {
             let x: i64 = row.get(0)?;
             let y: i64 = row.get(1)?;
             let user: i64 = row.get(2)?;
             let color: i64 = row.get(3)?;
+            if x as usize >= width || y as usize >= height {
+                continue;
+            }
             pixels_color[(x * height as i64 + y) as usize] = color as u8;
             pixels_user[(x * height as i64 + y) as usize] = user as u16;
         }
@@ -183,14 +1053,169 @@ impl Database {
         Ok((pixels_color, pixels_user))
     }
 
+    pub fn get_last_pixel_id(&self) -> Result<u64, DatabaseError> {
+        let connection = self.pool.get()?;
+        let pixel_id: i64 =
+            connection.query_row("SELECT COALESCE(MAX(pixel_id), 0) FROM pixels", [], |row| {
+                row.get(0)
+            })?;
+
+        Ok(pixel_id as u64)
+    }
+
+    /// Placements of a canvas with a pixel id above `seq`, in placement order
+    pub fn get_pixels_after(
+        &self,
+        canvas_id: u32,
+        seq: u64,
+    ) -> Result<Vec<DatabaseUpdate>, DatabaseError> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare(
+            "SELECT pixel_id, x, y, color, user, timestamp, restored
+            FROM pixels
+            WHERE pixel_id > ?1 AND canvas_id = ?2
+            ORDER BY pixel_id",
+        )?;
+
+        let mut rows = statement.query(params![seq as i64, canvas_id])?;
+        let mut updates = Vec::new();
+        while let Some(row) = rows.next()? {
+            let pixel_id: i64 = row.get(0)?;
+            let x: i64 = row.get(1)?;
+            let y: i64 = row.get(2)?;
+            let color: i64 = row.get(3)?;
+            let user: i64 = row.get(4)?;
+            let timestamp: i64 = row.get(5)?;
+            let restored: i64 = row.get(6)?;
+            updates.push(DatabaseUpdate {
+                seq: pixel_id as u64,
+                canvas_id,
+                x: x as usize,
+                y: y as usize,
+                color: color as u8,
+                user_id: user as u16,
+                timestamp,
+                restored: restored == 1,
+            });
+        }
+
+        Ok(updates)
+    }
+
+    pub fn get_pixel_history(
+        &self,
+        canvas_id: u32,
+        timestamp: i64,
+    ) -> Result<Vec<DatabaseUpdate>, DatabaseError> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare(
+            "SELECT pixel_id, x, y, color, user, timestamp, restored
+            FROM pixels
+            WHERE canvas_id = ?1 AND timestamp <= ?2
+            ORDER BY timestamp, pixel_id",
+        )?;
+
+        let mut rows = statement.query(params![canvas_id, timestamp])?;
+        let mut updates = Vec::new();
+        while let Some(row) = rows.next()? {
+            let pixel_id: i64 = row.get(0)?;
+            let x: i64 = row.get(1)?;
+            let y: i64 = row.get(2)?;
+            let color: i64 = row.get(3)?;
+            let user: i64 = row.get(4)?;
+            let timestamp: i64 = row.get(5)?;
+            let restored: i64 = row.get(6)?;
+            updates.push(DatabaseUpdate {
+                seq: pixel_id as u64,
+                canvas_id,
+                x: x as usize,
+                y: y as usize,
+                color: color as u8,
+                user_id: user as u16,
+                timestamp,
+                restored: restored == 1,
+            });
+        }
+
+        Ok(updates)
+    }
+
+    /// Pixels whose latest placement was made by `user_id` between `from` and `to`, with the
+    /// placement they covered that was not made by that user in the same window
+    pub fn get_rollback_pixels(
+        &self,
+        canvas_id: u32,
+        user_id: u16,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<RollbackPixel>, DatabaseError> {
+        let connection = self.pool.get()?;
+        let mut statement = connection.prepare(
+            "SELECT pixels.x, pixels.y, previous.color, previous.user
+            FROM pixels
+            LEFT JOIN pixels previous ON previous.pixel_id = (
+                SELECT MAX(pixel_id)
+                FROM pixels earlier
+                WHERE earlier.canvas_id = ?1 AND earlier.x = pixels.x AND earlier.y = pixels.y
+                AND NOT (earlier.user = ?2 AND earlier.timestamp BETWEEN ?3 AND ?4 AND earlier.restored = 0)
+            )
+            WHERE pixels.canvas_id = ?1 AND pixels.user = ?2
+            AND pixels.timestamp BETWEEN ?3 AND ?4 AND pixels.restored = 0
+            AND pixels.pixel_id = (
+                SELECT MAX(pixel_id)
+                FROM pixels latest
+                WHERE latest.canvas_id = ?1 AND latest.x = pixels.x AND latest.y = pixels.y
+            )",
+        )?;
+
+        let mut rows = statement.query(params![canvas_id, user_id, from, to])?;
+        let mut pixels = Vec::new();
+        while let Some(row) = rows.next()? {
+            let x: i64 = row.get(0)?;
+            let y: i64 = row.get(1)?;
+            let color: Option<i64> = row.get(2)?;
+            let user: Option<i64> = row.get(3)?;
+            pixels.push(RollbackPixel {
+                x: x as usize,
+                y: y as usize,
+                previous: color
+                    .zip(user)
+                    .map(|(color, user)| (color as u8, user as u16)),
+            });
+        }
+
+        Ok(pixels)
+    }
+
+    /// Marks the placements of a user in a time range as rolled back, so that they no longer
+    /// count in their score. Returns how many were marked
+    pub fn mark_rolled_back(
+        &self,
+        canvas_id: u32,
+        user_id: u16,
+        from: i64,
+        to: i64,
+    ) -> Result<u32, DatabaseError> {
+        let connection = self.pool.get()?;
+
+        let marked = connection.execute(
+            "UPDATE pixels SET rolled_back = 1
+            WHERE canvas_id = ?1 AND user = ?2 AND timestamp BETWEEN ?3 AND ?4
+            AND restored = 0 AND rolled_back = 0",
+            params![canvas_id, user_id, from, to],
+        )?;
+
+        Ok(marked as u32)
+    }
+
     pub fn get_users(&self) -> Result<HashMap<u16, User>, DatabaseError> {
         let connection = self.pool.get()?;
 
         let mut statement = connection.prepare(
-            "SELECT users.user_id, username, COALESCE(COUNT(pixels.user), 0) as pixel_count, verified
+            "SELECT users.user_id, username, COALESCE(COUNT(pixels.user), 0) as pixel_count, verified, role, banned, token_version
             FROM users
             LEFT JOIN pixels
-            ON users.user_id = pixels.user
+            ON users.user_id = pixels.user AND pixels.restored = 0 AND pixels.rolled_back = 0
             GROUP BY users.user_id",
         )?;
         let mut rows = statement.query([])?;
@@ -200,14 +1225,19 @@ impl Database {
             let username: String = row.get(1)?;
             let pixel_count: i64 = row.get(2)?;
             let verified: i64 = row.get(3)?;
+            let role: String = row.get(4)?;
+            let banned: i64 = row.get(5)?;
+            let token_version: i64 = row.get(6)?;
             users.insert(
                 id as u16,
                 User {
                     username,
-                    cooldown: 0,
                     rank: 0,
                     verified: verified == 1,
                     score: pixel_count as u32,
+                    role: role.parse().unwrap_or_default(),
+                    banned: banned == 1,
+                    token_version: token_version as u32,
                 },
             );
         }
@@ -242,16 +1272,19 @@ impl Database {
         let tx = connection.transaction()?;
         {
             let mut statement = tx.prepare(
-                "INSERT OR IGNORE INTO pixels (x, y, color, user, timestamp) VALUES (?1, ?2, ?3, ?4, ?5)",
+                "INSERT OR IGNORE INTO pixels (pixel_id, canvas_id, x, y, color, user, timestamp, restored) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             )?;
 
             for update in updates {
                 statement.execute(params![
+                    update.seq as i64,
+                    update.canvas_id,
                     update.x as i64,
                     update.y as i64,
                     update.color as i64,
                     update.user_id,
-                    update.timestamp
+                    update.timestamp,
+                    update.restored
                 ])?;
             }
         }
@@ -0,0 +1,104 @@
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+use actix::{Actor, ActorFutureExt, AsyncContext, Context, Handler, Message, WrapFuture};
+use actix_web::web;
+
+use crate::database::Database;
+use crate::models::appstate::{AppState, AppStateError};
+
+/// Held for the whole flush, while the placements taken from the canvases are being saved
+static FLUSH_LOCK: Mutex<()> = Mutex::new(());
+
+/// Saves the pending placements, then encodes the tiles, PNG and snapshots of every canvas.
+/// The app state is only locked to capture the pending work and to install the result.
+pub fn flush(appstate: &RwLock<AppState>, database: &Database) -> Result<(), AppStateError> {
+    let _guard = FLUSH_LOCK.lock().map_err(|_| AppStateError::LockError)?;
+
+    let jobs = appstate
+        .write()
+        .map_err(|_| AppStateError::LockError)?
+        .begin_flush();
+
+    let results = jobs.into_iter().map(|job| job.run(database)).collect();
+
+    appstate
+        .write()
+        .map_err(|_| AppStateError::LockError)?
+        .finish_flush(results);
+
+    Ok(())
+}
+
+/// Runs `f` once no flush is running, so that every placement no longer pending on a canvas
+/// is in the database, and keeps flushes from starting until it returns
+pub fn without_flush<T>(f: impl FnOnce() -> T) -> Result<T, AppStateError> {
+    let _guard = FLUSH_LOCK.lock().map_err(|_| AppStateError::LockError)?;
+    Ok(f())
+}
+
+/// Asks the flusher to flush now instead of waiting for the next interval
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Runs `flush` every `interval` on a blocking thread, outside the request path
+pub struct Flusher {
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    interval: Duration,
+    flushing: bool,
+}
+
+impl Flusher {
+    pub fn new(
+        appstate: web::Data<RwLock<AppState>>,
+        database: web::Data<Database>,
+        interval: Duration,
+    ) -> Self {
+        Self {
+            appstate,
+            database,
+            interval,
+            flushing: false,
+        }
+    }
+
+    fn start_flush(&mut self, ctx: &mut Context<Self>) {
+        if self.flushing {
+            return;
+        }
+        self.flushing = true;
+
+        let appstate = self.appstate.clone();
+        let database = self.database.clone();
+        ctx.spawn(
+            web::block(move || flush(&appstate, &database))
+                .into_actor(self)
+                .map(|result, act, _| {
+                    act.flushing = false;
+                    match result {
+                        Ok(Err(err)) => eprintln!("Error flushing canvases: {}", err),
+                        Err(err) => eprintln!("Error running flush: {}", err),
+                        Ok(Ok(())) => (),
+                    }
+                }),
+        );
+    }
+}
+
+impl Actor for Flusher {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.interval, |act, ctx| act.start_flush(ctx));
+    }
+}
+
+impl Handler<Flush> for Flusher {
+    type Result = ();
+
+    fn handle(&mut self, _: Flush, ctx: &mut Self::Context) {
+        self.start_flush(ctx);
+    }
+}
@@ -1,23 +1,49 @@
+mod cleanup;
+mod clock;
 mod database;
+mod flusher;
 mod models;
 mod routes;
 mod websocket;
 
-use crate::database::Database;
-use crate::models::appstate::AppState;
+use crate::cleanup::AccountCleanup;
+use crate::clock::EventClock;
+use crate::database::{blank_color, Database};
+use crate::flusher::{flush, Flusher};
+use crate::models::appstate::{canvas_colors, load_colors, AppState};
+use crate::models::canvas::DEFAULT_CANVAS;
+use crate::models::keys::JwtKeys;
+use crate::models::timelapse::{render_timelapse, TimelapseOptions};
+use crate::models::user::Role;
+use crate::models::utils::parse_palette;
+use crate::routes::admin::{
+    add_color, ban_user, create_canvas, create_mask_region, create_region, delete_region,
+    freeze_canvas, get_moderation_log, resize_canvas, retire_color, rollback_user, set_schedule,
+    set_user_role, unban_user, unfreeze_canvas, unretire_color, unverify_user, verify_user,
+};
 use crate::routes::place::{
-    draw, get_leaderboard, get_png, get_size, get_updates, get_username, get_users_connected,
-    get_users_count,
+    draw, get_canvases, get_leaderboard, get_palette, get_png, get_png_at, get_region_mask,
+    get_regions, get_size, get_status, get_tile, get_tile_layout, get_timelapse, get_updates,
+    get_username, get_users_connected, get_users_count,
+};
+use crate::routes::user::{
+    edit_profile, forgot_password, get_profile, login, logout, logout_all, refresh_session,
+    resend_verification, reset_password, signup, verify,
 };
-use crate::routes::user::{edit_profile, get_profile, login, signup, verify};
 use crate::websocket::ws_index;
+use actix::Actor;
 use actix_cors::Cors;
 use actix_files::Files;
 use actix_governor::{Governor, GovernorConfigBuilder};
+use actix_web::dev::ServerHandle;
+use actix_web::rt::{
+    self,
+    signal::unix::{signal, SignalKind},
+};
 use actix_web::{web, App, HttpServer};
 use dotenv::dotenv;
 use std::sync::RwLock;
-use std::{env, io};
+use std::{env, fs, io};
 
 #[actix_web::main]
 async fn main() -> io::Result<()> {
@@ -33,6 +59,14 @@ async fn main() -> io::Result<()> {
         .parse()
         .expect("HEIGHT should be a valid usize");
 
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("timelapse") {
+        return export_timelapse(width, height, &args[2..]);
+    }
+    if args.get(1).map(String::as_str) == Some("role") {
+        return set_role(&args[2..]);
+    }
+
     let bind_address = env::var("BIND_ADDRESS").expect("BIND_ADDRESS must be set");
 
     let port: u16 = env::var("PORT")
@@ -54,15 +88,18 @@ async fn main() -> io::Result<()> {
 
     database.create_tables().expect("Error creating tables");
 
-    let mut appstate = AppState::new(width, height, &database).expect("Error creating appstate");
+    let appstate = AppState::new(width, height, &database).expect("Error creating appstate");
+    let update_interval = appstate.update_interval();
 
-    let mut database = web::Data::new(database);
+    let database = web::Data::new(database);
+    let appstate = web::Data::new(RwLock::new(appstate));
 
-    appstate
-        .try_update(&mut database)
-        .expect("Error updating appstate");
+    flush(&appstate, &database).expect("Error flushing canvases");
 
-    let appstate = web::Data::new(RwLock::new(appstate));
+    let flusher =
+        web::Data::new(Flusher::new(appstate.clone(), database.clone(), update_interval).start());
+    let _clock = EventClock::new(appstate.clone(), flusher.get_ref().clone()).start();
+    let _cleanup = AccountCleanup::new(appstate.clone(), database.clone()).start();
 
     let governor_conf = GovernorConfigBuilder::default()
         .per_second(per_second)
@@ -70,25 +107,42 @@ async fn main() -> io::Result<()> {
         .finish()
         .expect("Error creating governor config");
 
-    HttpServer::new(move || {
-        let app = App::new()
+    let shutdown_appstate = appstate.clone();
+    let shutdown_database = database.clone();
+    let reload_appstate = appstate.clone();
+    let reload_database = database.clone();
+
+    let server = HttpServer::new(move || {
+        App::new()
             .wrap(
                 Cors::default()
                     .allow_any_origin()
                     .allow_any_method()
                     .allow_any_header()
+                    .expose_headers(["X-Sequence", "ETag"])
                     .max_age(3600),
             )
             .wrap(Governor::new(&governor_conf))
             .app_data(appstate.clone())
             .app_data(database.clone())
+            .app_data(flusher.clone())
             .service(get_png)
+            .service(get_png_at)
+            .service(get_tile)
+            .service(get_tile_layout)
+            .service(get_timelapse)
             .service(get_updates)
             .service(draw)
             .service(login)
             .service(signup)
+            .service(refresh_session)
+            .service(logout)
+            .service(logout_all)
             .service(get_leaderboard)
             .service(verify)
+            .service(resend_verification)
+            .service(forgot_password)
+            .service(reset_password)
             .service(ws_index)
             .service(get_size)
             .service(get_profile)
@@ -96,11 +150,226 @@ async fn main() -> io::Result<()> {
             .service(get_users_count)
             .service(get_users_connected)
             .service(get_username)
-            .service(Files::new("/", "/var/www/html/").index_file("index.html"));
-
-        app
+            .service(resize_canvas)
+            .service(get_canvases)
+            .service(create_canvas)
+            .service(ban_user)
+            .service(unban_user)
+            .service(verify_user)
+            .service(unverify_user)
+            .service(set_user_role)
+            .service(rollback_user)
+            .service(get_moderation_log)
+            .service(create_region)
+            .service(create_mask_region)
+            .service(delete_region)
+            .service(get_regions)
+            .service(get_region_mask)
+            .service(get_status)
+            .service(set_schedule)
+            .service(freeze_canvas)
+            .service(unfreeze_canvas)
+            .service(get_palette)
+            .service(add_color)
+            .service(retire_color)
+            .service(unretire_color)
+            .service(Files::new("/", "/var/www/html/").index_file("index.html"))
     })
     .bind((bind_address, port))?
-    .run()
-    .await
+    .disable_signals()
+    .run();
+
+    handle_shutdown(server.handle(), shutdown_appstate, shutdown_database)?;
+    handle_reload(reload_appstate, reload_database)?;
+
+    server.await
+}
+
+/// On SIGTERM or SIGINT, refuses new placements, closes the WebSockets, saves the pending
+/// placements and stops the server
+fn handle_shutdown(
+    server: ServerHandle,
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+) -> io::Result<()> {
+    for kind in [SignalKind::terminate(), SignalKind::interrupt()] {
+        let mut signal = signal(kind)?;
+        let server = server.clone();
+        let appstate = appstate.clone();
+        let database = database.clone();
+
+        rt::spawn(async move {
+            signal.recv().await;
+
+            let started = appstate
+                .write()
+                .map(|mut appstate| appstate.shutdown("server shutting down"))
+                .unwrap_or(true);
+            if !started {
+                return;
+            }
+
+            println!("Shutting down, saving pending placements");
+            match web::block(move || flush(&appstate, &database)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => eprintln!("Error flushing canvases: {}", err),
+                Err(err) => eprintln!("Error running flush: {}", err),
+            }
+
+            server.stop(true).await;
+        });
+    }
+
+    Ok(())
+}
+
+/// On SIGHUP, reloads the JWT keys so they can be rotated without a restart, and the user roles
+/// set with `place-rs role`. Invalid keys are reported and the previous ones kept
+fn handle_reload(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+) -> io::Result<()> {
+    let mut signal = signal(SignalKind::hangup())?;
+
+    rt::spawn(async move {
+        while signal.recv().await.is_some() {
+            let database = database.clone();
+            match web::block(move || database.get_roles()).await {
+                Ok(Ok(roles)) => {
+                    if let Ok(mut appstate) = appstate.write() {
+                        appstate.set_roles(&roles);
+                    }
+                    println!("Reloaded user roles");
+                }
+                Ok(Err(err)) => eprintln!("Error loading user roles: {}", err),
+                Err(err) => eprintln!("Error running role reload: {}", err),
+            }
+
+            let jwt_keys = match JwtKeys::from_env() {
+                Ok(jwt_keys) => jwt_keys,
+                Err(err) => {
+                    eprintln!("Keeping the previous JWT keys: {}", err);
+                    continue;
+                }
+            };
+
+            println!(
+                "Reloaded {} JWT keys, signing with {}",
+                jwt_keys.key_count(),
+                jwt_keys.current()
+            );
+            if let Ok(mut appstate) = appstate.write() {
+                appstate.set_jwt_keys(jwt_keys);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// `place-rs timelapse <output.gif> [--canvas name] [--interval s] [--delay ms] [--region x,y,w,h] [--scale n] [--from ts] [--to ts]`
+fn export_timelapse(width: usize, height: usize, args: &[String]) -> io::Result<()> {
+    let output = args.first().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "usage: place-rs timelapse <output.gif> [options]",
+        )
+    })?;
+
+    let mut canvas_name = DEFAULT_CANVAS.to_string();
+    let mut option_args = Vec::new();
+    let mut args = args[1..].iter();
+    while let Some(arg) = args.next() {
+        if arg == "--canvas" {
+            canvas_name = args.next().cloned().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "--canvas needs a value")
+            })?;
+        } else {
+            option_args.push(arg.clone());
+        }
+    }
+
+    let options = TimelapseOptions::from_args(&option_args)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+    let database = Database::new().expect("Error connecting to database");
+    database.create_tables().expect("Error creating tables");
+    let default_colors = load_colors().expect("Error loading palette");
+
+    let info = database
+        .get_canvases()
+        .expect("Error fetching canvases")
+        .into_iter()
+        .find(|info| info.name == canvas_name);
+
+    let (canvas_id, width, height, colors, blank) = match info {
+        Some(info) => (
+            info.canvas_id,
+            info.width,
+            info.height,
+            canvas_colors(&info, &default_colors).expect("Error loading palette"),
+            info.blank_color,
+        ),
+        None if canvas_name == DEFAULT_CANVAS => (1, width, height, default_colors, None),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no canvas named {}", canvas_name),
+            ))
+        }
+    };
+    let palette = parse_palette(&colors)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+
+    let updates = database
+        .get_pixel_history(canvas_id, options.to.unwrap_or(i64::MAX))
+        .expect("Error fetching pixel history");
+
+    let blank = blank.unwrap_or_else(|| blank_color(palette.len()));
+    let gif = render_timelapse(&updates, width, height, &palette, blank, &options, None)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+    fs::write(output, gif)?;
+    println!("Timelapse written to {}", output);
+
+    Ok(())
+}
+
+/// `place-rs role <username> <user|moderator|admin>`
+fn set_role(args: &[String]) -> io::Result<()> {
+    let (username, role) = match args {
+        [username, role] => (username, role),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "usage: place-rs role <username> <user|moderator|admin>",
+            ))
+        }
+    };
+    let role: Role = role
+        .parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    let database = Database::new().expect("Error connecting to database");
+    database.create_tables().expect("Error creating tables");
+
+    let user_id = database
+        .get_user_id(username)
+        .expect("Error fetching user")
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no user named {}", username),
+            )
+        })?;
+    database
+        .set_role(user_id, role)
+        .expect("Error setting role");
+    println!(
+        "{} is now {}, send SIGHUP to a running server to apply it",
+        username,
+        role.as_str()
+    );
+
+    Ok(())
 }
@@ -1,19 +1,34 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, fs};
 
-use actix::Addr;
-use actix_web::web;
-use chrono::Utc;
-use image::{ImageBuffer, Rgb};
-use lettre::{transport::smtp, Transport};
 use regex::Regex;
 use thiserror::Error;
 
-use crate::database::{Database, DatabaseUpdate};
-use crate::models::user::User;
-use crate::models::utils::{hex_to_rgb, ColorFile};
-use crate::websocket::{MessageUpdate, PlaceWebSocketConnection};
+use crate::database::{CanvasInfo, Database, DatabaseUpdate, RollbackPixel};
+use crate::models::canvas::{Canvas, CanvasSummary, FlushJob, FlushResult, DEFAULT_CANVAS};
+use crate::models::email::{EmailDomains, EmailError};
+use crate::models::event::EventSchedule;
+use crate::models::keys::{JwtKeys, KeysError};
+use crate::models::mailer::{self, Email, Mailer, MailerError};
+use crate::models::snapshot::SnapshotError;
+use crate::models::throttle::Throttle;
+use crate::models::user::{Role, User};
+use crate::models::utils::{parse_palette, ColorFile};
+
+/// How long a password reset link can be used
+pub const RESET_TOKEN_LIFETIME_SEC: i64 = 60 * 60;
+/// Password reset requests allowed per email in `RESET_REQUEST_WINDOW_SEC`
+const RESET_REQUEST_LIMIT: usize = 3;
+const RESET_REQUEST_WINDOW_SEC: i64 = 60 * 60;
+/// How long a verification link can be used
+pub const VERIFICATION_CODE_LIFETIME_SEC: i64 = 24 * 60 * 60;
+/// Verification emails a user can ask for again in `RESEND_WINDOW_SEC`
+const RESEND_LIMIT: usize = 3;
+const RESEND_WINDOW_SEC: i64 = 60 * 60;
 
 #[derive(Error, Debug)]
 pub enum AppStateError {
@@ -21,8 +36,12 @@ pub enum AppStateError {
     PixelFetchError(String),
     #[error("Error getting users")]
     UserFetchError,
-    #[error("SMTP configuration error")]
-    SmtpConfigError,
+    #[error("Email domains error: {0}")]
+    EmailError(#[from] EmailError),
+    #[error("JWT keys error: {0}")]
+    KeysError(#[from] KeysError),
+    #[error("Mailer error: {0}")]
+    MailerError(#[from] MailerError),
     #[error("Regex compilation error")]
     RegexCompileError,
     #[error("File read error: {0}")]
@@ -33,258 +52,465 @@ pub enum AppStateError {
     EnvVarNotSet(String),
     #[error("Invalid value: {0}")]
     InvalidValueError(String),
-    #[error("Error parsing email")]
-    EmailParseError,
-    #[error("Error creating verification email")]
-    EmailCreationError,
-    #[error("Error sending verification email")]
-    EmailSendingError,
     #[error("Error adding session")]
     SessionAddError,
+    #[error("Error removing session")]
+    SessionRemoveError,
     #[error("No such user")]
     NoSuchUserError,
+    #[error("Database error: {0}")]
+    DatabaseError(String),
+    #[error("No such canvas")]
+    NoSuchCanvasError,
+    #[error("Sequence number no longer available")]
+    SequenceExpiredError,
+    #[error("App state lock poisoned")]
+    LockError,
+    #[error("Snapshot error: {0}")]
+    SnapshotError(#[from] SnapshotError),
+}
+
+/// Hex colors of the default palette, read from `COLORS_PATH`
+pub fn load_colors() -> Result<Vec<String>, AppStateError> {
+    let colors_path = env::var("COLORS_PATH")
+        .map_err(|_| AppStateError::EnvVarNotSet("COLORS_PATH".to_string()))?;
+    let colors_str = fs::read_to_string(colors_path).map_err(AppStateError::FileReadError)?;
+    let color_file =
+        serde_json::from_str::<ColorFile>(&colors_str).map_err(AppStateError::JsonParseError)?;
+    parse_palette(&color_file.colors)?;
+
+    Ok(color_file.colors)
+}
+
+/// Hex colors of a canvas, falling back to the default palette
+pub fn canvas_colors(
+    info: &CanvasInfo,
+    default_colors: &[String],
+) -> Result<Vec<String>, AppStateError> {
+    match &info.palette {
+        Some(palette) => serde_json::from_str(palette).map_err(AppStateError::JsonParseError),
+        None => Ok(default_colors.to_vec()),
+    }
+}
+
+/// Sends the account emails. It is cloned out of the appstate so that mail goes out without
+/// holding its lock
+#[derive(Clone)]
+pub struct AccountMailer {
+    mailer: Arc<dyn Mailer>,
+    url: String,
+}
+
+impl AccountMailer {
+    pub fn send_verification_mail(&self, email: &str, token: &str) -> Result<(), MailerError> {
+        self.mailer.send(&Email {
+            to: email.to_string(),
+            subject: "Verify your account".to_string(),
+            body: format!(
+                "Click on this link to verify your account: {}/api/verify/{}\n\
+                The link expires in {} hours.",
+                self.url,
+                token,
+                VERIFICATION_CODE_LIFETIME_SEC / 3600,
+            ),
+        })
+    }
+
+    pub fn send_password_reset_mail(
+        &self,
+        email: &str,
+        username: &str,
+        token: &str,
+    ) -> Result<(), MailerError> {
+        self.mailer.send(&Email {
+            to: email.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Click on this link to choose a new password for {}: {}/?reset={}\n\
+                The link expires in {} minutes. If you did not ask for it, you can ignore this email.",
+                username,
+                self.url,
+                token,
+                RESET_TOKEN_LIFETIME_SEC / 60,
+            ),
+        })
+    }
 }
 
 pub struct AppState {
-    width: usize,
-    height: usize,
-    pixels_color: Vec<u8>,
-    pixels_user: Vec<u16>,
-    palette: Vec<(u8, u8, u8)>,
+    canvases: HashMap<String, Canvas>,
     users: HashMap<u16, User>,
-    png: Vec<u8>,
-    last_update: i64,
+    last_pixel_id: u64,
     update_cooldown: u16,
-    database_updates: Vec<DatabaseUpdate>,
-    message_updates: Vec<MessageUpdate>,
-    mailer: lettre::SmtpTransport,
-    sessions: RwLock<Vec<Addr<PlaceWebSocketConnection>>>,
+    snapshot_dir: Option<PathBuf>,
+    snapshot_interval: i64,
+    closing: bool,
+    mailer: AccountMailer,
+    reset_throttle: Throttle,
+    resend_throttle: Throttle,
+    unverified_lifetime: i64,
     email_regex: Regex,
+    email_domains: EmailDomains,
     cooldown: u16,
-    jwt_secret: String,
-    smtp_user: String,
-    url: String,
+    colors: Vec<String>,
+    jwt_keys: JwtKeys,
 }
 
 impl AppState {
+    /// Loads every canvas, creating the default one with `width` and `height` on first start
     pub fn new(width: usize, height: usize, db: &Database) -> Result<Self, AppStateError> {
-        let (pixels_color, pixels_user) = db
-            .get_pixels(width, height)
+        let last_pixel_id = db
+            .get_last_pixel_id()
             .map_err(|e| AppStateError::PixelFetchError(e.to_string()))?;
 
         let users = db.get_users().map_err(|_| AppStateError::UserFetchError)?;
 
-        let smtp_server = env::var("SMTP_SERVER")
-            .map_err(|_| AppStateError::EnvVarNotSet("SMTP_SERVER".to_string()))?;
-        let smtp_port: u16 = env::var("SMTP_PORT")
-            .map_err(|_| AppStateError::EnvVarNotSet("SMTP_PORT".to_string()))?
-            .parse()
-            .map_err(|_| AppStateError::InvalidValueError("SMTP_PORT".to_string()))?;
-        let smtp_user = env::var("SMTP_USER")
-            .map_err(|_| AppStateError::EnvVarNotSet("SMTP_USER".to_string()))?;
-        let smtp_password = env::var("SMTP_PASSWORD")
-            .map_err(|_| AppStateError::EnvVarNotSet("SMTP_PASSWORD".to_string()))?;
-
-        let mailer = lettre::SmtpTransport::starttls_relay(&smtp_server)
-            .map_err(|_| AppStateError::SmtpConfigError)?
-            .port(smtp_port)
-            .credentials(smtp::authentication::Credentials::new(
-                smtp_user.clone(),
-                smtp_password,
-            ))
-            .build();
+        let mailer = mailer::from_env()?;
 
         let email_regex =
             Regex::new(r"^[a-zA-Z0-9.!#$%&'*+/=?^_`{|}~-]+@[a-zA-Z0-9-]+(?:\.[a-zA-Z0-9-]+)*$")
                 .map_err(|_| AppStateError::RegexCompileError)?;
+        let email_domains = EmailDomains::from_env()?;
 
         let cooldown = env::var("COOLDOWN_SEC")
             .map_err(|_| AppStateError::EnvVarNotSet("COOLDOWN_SEC".to_string()))?
             .parse::<u16>()
             .map_err(|_| AppStateError::InvalidValueError("COOLDOWN".to_string()))?;
 
-        let jwt_secret = env::var("JWT_SECRET")
-            .map_err(|_| AppStateError::EnvVarNotSet("JWT_SECRET".to_string()))?;
+        let jwt_keys = JwtKeys::from_env()?;
 
         let update_cooldown = env::var("UPDATE_COOLDOWN_SEC")
             .map_err(|_| AppStateError::EnvVarNotSet("UPDATE_COOLDOWN_SEC".to_string()))?
             .parse::<u16>()
             .map_err(|_| AppStateError::InvalidValueError("UPDATE_COOLDOWN_SEC".to_string()))?;
 
-        let colors_path = env::var("COLORS_PATH")
-            .map_err(|_| AppStateError::EnvVarNotSet("COLORS_PATH".to_string()))?;
-        let colors_str = fs::read_to_string(colors_path).map_err(AppStateError::FileReadError)?;
-        let color_file = serde_json::from_str::<ColorFile>(&colors_str)
-            .map_err(AppStateError::JsonParseError)?;
-        let palette: Vec<(u8, u8, u8)> = color_file
-            .colors
-            .iter()
-            .map(|color| hex_to_rgb(color))
-            .collect();
+        let snapshot_dir = env::var("SNAPSHOT_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from);
+
+        let snapshot_interval = match env::var("SNAPSHOT_INTERVAL_SEC") {
+            Ok(interval) => interval.parse::<i64>().map_err(|_| {
+                AppStateError::InvalidValueError("SNAPSHOT_INTERVAL_SEC".to_string())
+            })?,
+            Err(_) => 300,
+        };
+
+        let unverified_lifetime = match env::var("UNVERIFIED_ACCOUNT_LIFETIME_SEC") {
+            Ok(lifetime) => lifetime.parse::<i64>().map_err(|_| {
+                AppStateError::InvalidValueError("UNVERIFIED_ACCOUNT_LIFETIME_SEC".to_string())
+            })?,
+            Err(_) => 7 * 24 * 60 * 60,
+        };
+
+        let colors = load_colors()?;
 
         let url = env::var("URL").map_err(|_| AppStateError::EnvVarNotSet("URL".to_string()))?;
 
+        let mut infos = db
+            .get_canvases()
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+        if infos.is_empty() {
+            db.create_canvas(DEFAULT_CANVAS, width, height, None, None)
+                .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+            infos = db
+                .get_canvases()
+                .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+        }
+
+        let mut canvases = HashMap::new();
+        for info in infos {
+            if info.name == DEFAULT_CANVAS && (info.width, info.height) != (width, height) {
+                println!(
+                    "Using the saved canvas size {}x{} instead of WIDTH and HEIGHT",
+                    info.width, info.height
+                );
+            }
+            let canvas_colors = canvas_colors(&info, &colors)?;
+            let canvas = Canvas::load(
+                info,
+                cooldown,
+                canvas_colors,
+                last_pixel_id,
+                snapshot_dir.as_deref(),
+                db,
+            )?;
+            canvases.insert(canvas.name().to_string(), canvas);
+        }
+
         Ok(Self {
-            width,
-            height,
-            pixels_color,
-            pixels_user,
-            palette,
+            canvases,
             users,
-            last_update: 0,
+            last_pixel_id,
             update_cooldown,
-            database_updates: Vec::new(),
-            message_updates: Vec::new(),
-            mailer,
-            sessions: RwLock::new(Vec::new()),
+            snapshot_dir,
+            snapshot_interval,
+            closing: false,
+            mailer: AccountMailer {
+                mailer: Arc::from(mailer),
+                url,
+            },
+            reset_throttle: Throttle::new(RESET_REQUEST_LIMIT, RESET_REQUEST_WINDOW_SEC),
+            resend_throttle: Throttle::new(RESEND_LIMIT, RESEND_WINDOW_SEC),
+            unverified_lifetime,
             email_regex,
+            email_domains,
             cooldown,
-            jwt_secret,
-            png: Vec::new(),
-            smtp_user,
-            url,
+            colors,
+            jwt_keys,
         })
     }
 
     pub fn draw(
         &mut self,
+        canvas: &str,
         x: usize,
         y: usize,
         user_id: u16,
         color: u8,
     ) -> Result<(), AppStateError> {
-        if x >= self.width || y >= self.height {
-            return Err(AppStateError::InvalidValueError(
-                "x or y out of bounds".to_string(),
-            ));
-        }
-
-        let index = x * self.height + y;
-        self.pixels_user[index] = user_id;
-        self.pixels_color[index] = color;
-
+        let canvas = self
+            .canvases
+            .get_mut(canvas)
+            .ok_or(AppStateError::NoSuchCanvasError)?;
         let user = self
             .users
             .get_mut(&user_id)
-            .ok_or_else(|| AppStateError::NoSuchUserError)?;
-        user.cooldown = Utc::now().timestamp() + self.cooldown as i64;
-        user.score += 1;
+            .ok_or(AppStateError::NoSuchUserError)?;
 
-        self.database_updates.push(DatabaseUpdate {
-            x,
-            y,
-            color,
-            user_id,
-            timestamp: Utc::now().timestamp(),
-        });
-        let message_update = MessageUpdate { x, y, color };
-        self.message_updates.push(message_update);
-        self.broadcast(message_update)?;
+        canvas.draw(x, y, user_id, color, self.last_pixel_id + 1)?;
+        self.last_pixel_id += 1;
+        user.score += 1;
 
         Ok(())
     }
 
-    pub fn send_verification_mail(&self, email: &str, token: &str) -> Result<(), AppStateError> {
-        let parsed_from = self
-            .smtp_user
-            .parse()
-            .map_err(|_| AppStateError::EmailParseError)?;
-        let parsed_to = email.parse().map_err(|_| AppStateError::EmailParseError)?;
-
-        let email_body = format!(
-            "Click on this link to verify your account: {}/api/verify/{}",
-            self.url, token,
-        );
-
-        let email = lettre::Message::builder()
-            .from(parsed_from)
-            .to(parsed_to)
-            .subject("Verify your account")
-            .body(email_body)
-            .map_err(|_| AppStateError::EmailCreationError)?;
-
-        self.mailer
-            .send(&email)
-            .map_err(|_| AppStateError::EmailSendingError)?;
-        Ok(())
+    /// Id and pending placements of a canvas, which a rollback saves before looking for the
+    /// pixels to put back
+    pub fn pending_updates(
+        &self,
+        canvas: &str,
+    ) -> Result<(u32, Vec<DatabaseUpdate>), AppStateError> {
+        let canvas = self
+            .canvases
+            .get(canvas)
+            .ok_or(AppStateError::NoSuchCanvasError)?;
+
+        Ok((canvas.id(), canvas.pending_updates().to_vec()))
     }
 
-    pub fn add_session(
-        &self,
-        session: Addr<PlaceWebSocketConnection>,
-    ) -> Result<(), AppStateError> {
-        self.sessions
-            .write()
-            .map(|mut sessions| sessions.push(session))
-            .map_err(|_| AppStateError::SessionAddError)
-    }
-
-    fn broadcast(&self, msg: MessageUpdate) -> Result<(), AppStateError> {
-        let sessions = self
-            .sessions
-            .read()
-            .map_err(|_| AppStateError::SessionAddError)?;
-        for session in sessions.iter() {
-            session.do_send(msg);
+    /// Puts back what the placements of `user_id` found by a rollback covered, on the pixels
+    /// where one of them is still the latest. Pixels that were blank before are restored in the
+    /// name of `actor_id`. The restored placements are saved by the next flush, and the
+    /// `rolled_back` placements are taken off the user's score. Returns the number of pixels
+    /// changed
+    pub fn restore_pixels(
+        &mut self,
+        canvas: &str,
+        user_id: u16,
+        actor_id: u16,
+        pixels: &[RollbackPixel],
+        rolled_back: u32,
+    ) -> Result<usize, AppStateError> {
+        let canvas = self
+            .canvases
+            .get_mut(canvas)
+            .ok_or(AppStateError::NoSuchCanvasError)?;
+
+        let blank = (canvas.blank_color(), actor_id);
+        let (width, height) = canvas.get_size();
+        let mut restored = 0;
+        for pixel in pixels {
+            // Someone may have drawn over it since the rollback looked it up
+            if pixel.x >= width
+                || pixel.y >= height
+                || canvas.get_pixel_user(pixel.x, pixel.y) != user_id
+            {
+                continue;
+            }
+            let (color, user) = pixel.previous.unwrap_or(blank);
+            canvas.restore(pixel.x, pixel.y, user, color, self.last_pixel_id + 1)?;
+            self.last_pixel_id += 1;
+            restored += 1;
         }
-        Ok(())
-    }
 
-    pub fn try_update(&mut self, db: &web::Data<Database>) -> Result<(), AppStateError> {
-        let time = Utc::now().timestamp();
-        if time - self.last_update < self.update_cooldown as i64 {
-            return Ok(());
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.score = user.score.saturating_sub(rolled_back);
         }
-        self.last_update = time;
-
-        let image = ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
-            let index = (x as usize) * self.height + (y as usize);
-            let color = self.palette[self.pixels_color[index] as usize];
-            Rgb([color.0, color.1, color.2])
-        });
-
-        let mut new_png: Vec<u8> = Vec::new();
-        {
-            let mut cursor = std::io::Cursor::new(&mut new_png);
-            image
-                .write_to(&mut cursor, image::ImageOutputFormat::Png)
-                .map_err(|_| {
-                    AppStateError::FileReadError(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Error writing image",
-                    ))
-                })?;
+
+        Ok(restored)
+    }
+
+    pub fn create_canvas(
+        &mut self,
+        name: &str,
+        width: usize,
+        height: usize,
+        cooldown: Option<u16>,
+        colors: Option<Vec<String>>,
+        db: &Database,
+    ) -> Result<&Canvas, AppStateError> {
+        if self.canvases.contains_key(name) {
+            return Err(AppStateError::InvalidValueError(
+                "canvas already exists".to_string(),
+            ));
         }
 
-        self.png = new_png;
+        let palette = colors
+            .map(|colors| serde_json::to_string(&colors))
+            .transpose()
+            .map_err(AppStateError::JsonParseError)?;
+
+        let canvas_id = db
+            .create_canvas(name, width, height, cooldown, palette.as_deref())
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+
+        let info = CanvasInfo {
+            canvas_id,
+            name: name.to_string(),
+            width,
+            height,
+            cooldown,
+            palette,
+            retired_colors: None,
+            blank_color: None,
+            close_snapshot: None,
+            schedule: EventSchedule::default(),
+        };
+        let canvas_colors = canvas_colors(&info, &self.colors)?;
+        let canvas = Canvas::load(
+            info,
+            self.cooldown,
+            canvas_colors,
+            self.last_pixel_id,
+            self.snapshot_dir.as_deref(),
+            db,
+        )?;
+
+        Ok(self.canvases.entry(name.to_string()).or_insert(canvas))
+    }
+
+    /// Whether the mailer reaches users, see `Mailer::delivers`
+    pub fn sends_mail(&self) -> bool {
+        self.mailer.mailer.delivers()
+    }
+
+    pub fn mailer(&self) -> AccountMailer {
+        self.mailer.clone()
+    }
+
+    /// Records a password reset request for `email`, false if it made too many lately
+    pub fn allow_password_reset(&mut self, email: &str, time: i64) -> bool {
+        self.reset_throttle.allow(&email.to_lowercase(), time)
+    }
+
+    /// Records a request of a user for another verification email, false if they made too
+    /// many lately
+    pub fn allow_verification_resend(&mut self, user_id: u16, time: i64) -> bool {
+        self.resend_throttle.allow(&user_id.to_string(), time)
+    }
 
-        db.save_pixel_updates(&self.database_updates).map_err(|e| {
-            eprintln!("Error saving pixel updates: {}", e);
-            AppStateError::PixelFetchError(e.to_string())
-        })?;
+    /// Seconds an account can stay unverified before it is deleted
+    pub fn unverified_lifetime(&self) -> i64 {
+        self.unverified_lifetime
+    }
+
+    /// Captures the pending work of every canvas, see `Canvas::begin_flush`
+    pub fn begin_flush(&mut self) -> Vec<FlushJob> {
+        let interval = if self.closing {
+            0
+        } else {
+            self.snapshot_interval
+        };
+        let snapshot = self.snapshot_dir.as_deref().map(|dir| (dir, interval));
+
+        self.canvases
+            .values_mut()
+            .map(|canvas| canvas.begin_flush(snapshot))
+            .collect()
+    }
+
+    /// Installs the results of the flush jobs and recomputes the leaderboard ranks
+    pub fn finish_flush(&mut self, results: Vec<FlushResult>) {
+        for result in results {
+            if let Some(canvas) = self.canvases.get_mut(result.canvas()) {
+                canvas.finish_flush(result);
+            }
+        }
 
         let mut users: Vec<&mut User> = self.users.values_mut().collect();
-        users.sort_by(|a, b| b.score.cmp(&a.score));
+        users.sort_by_key(|user| Reverse(user.score));
         for (rank, user) in users.iter_mut().enumerate() {
             user.rank = rank as u32 + 1;
         }
+    }
 
-        self.database_updates.clear();
-        self.message_updates.clear();
+    /// Refuses new placements and closes every WebSocket with `reason`. Returns `false` if
+    /// the shutdown had already started
+    pub fn shutdown(&mut self, reason: &str) -> bool {
+        if self.closing {
+            return false;
+        }
+        self.closing = true;
 
-        Ok(())
+        for canvas in self.canvases.values() {
+            canvas
+                .close_sessions(reason)
+                .unwrap_or_else(|err| eprintln!("Error closing sessions: {}", err));
+        }
+
+        true
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
     }
 
-    pub fn get_size(&self) -> (usize, usize) {
-        (self.width, self.height)
+    /// Time between two flushes of the pending placements
+    pub fn update_interval(&self) -> Duration {
+        Duration::from_secs(self.update_cooldown.max(1) as u64)
     }
 
-    pub fn get_png(&self) -> &Vec<u8> {
-        &self.png
+    pub fn get_canvas(&self, name: &str) -> Option<&Canvas> {
+        self.canvases.get(name)
     }
 
-    pub fn get_message_updates(&self) -> Vec<MessageUpdate> {
-        self.message_updates.clone()
+    pub fn get_canvas_mut(&mut self, name: &str) -> Option<&mut Canvas> {
+        self.canvases.get_mut(name)
+    }
+
+    /// Whether `update_events` has anything to do at `time`, checked under a read lock first
+    pub fn events_due(&self, time: i64) -> bool {
+        self.canvases
+            .values()
+            .any(|canvas| canvas.event_update_due(time))
+    }
+
+    /// Updates the event state of every canvas, `true` if one of them just closed
+    pub fn update_events(&mut self, time: i64) -> bool {
+        let mut closed = false;
+        for canvas in self.canvases.values_mut() {
+            match canvas.update_event(time) {
+                Ok(canvas_closed) => closed |= canvas_closed,
+                Err(err) => eprintln!("Error updating event of {}: {}", canvas.name(), err),
+            }
+        }
+        closed
+    }
+
+    /// Removes a protected region from the canvas holding it, returning the canvas name
+    pub fn remove_region(&mut self, region_id: u32) -> Option<String> {
+        self.canvases.values_mut().find_map(|canvas| {
+            canvas
+                .remove_region(region_id)
+                .then(|| canvas.name().to_string())
+        })
+    }
+
+    pub fn get_canvases(&self) -> Vec<CanvasSummary> {
+        let mut canvases: Vec<&Canvas> = self.canvases.values().collect();
+        canvases.sort_by_key(|canvas| canvas.id());
+        canvases.iter().map(|canvas| canvas.summary()).collect()
     }
 
     pub fn get_user(&self, id: u16) -> Option<&User> {
@@ -299,9 +525,26 @@ impl AppState {
         self.users.insert(id, user);
     }
 
+    /// Logs a user out of every device, see `Database::revoke_tokens`
+    pub fn revoke_tokens(&mut self, user_id: u16, db: &Database) -> Result<(), AppStateError> {
+        let version = db
+            .revoke_tokens(user_id)
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+
+        if let Some(user) = self.users.get_mut(&user_id) {
+            user.token_version = version;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_user(&mut self, id: u16) {
+        self.users.remove(&id);
+    }
+
     pub fn get_leaderboard(&self) -> Vec<User> {
         let mut users: Vec<User> = self.users.values().cloned().collect();
-        users.sort_by(|a, b| a.rank.cmp(&b.rank));
+        users.sort_by_key(|user| user.rank);
         users.into_iter().take(10).collect()
     }
 
@@ -313,13 +556,8 @@ impl AppState {
         self.users.len()
     }
 
-    pub fn get_users_connected(&self) -> usize {
-        self.sessions.read().unwrap().len()
-    }
-
-    pub fn get_username_from_pixel(&self, x: usize, y: usize) -> String {
-        let index = x * self.height + y;
-        let user_id = self.pixels_user[index];
+    pub fn get_username_from_pixel(&self, canvas: &Canvas, x: usize, y: usize) -> String {
+        let user_id = canvas.get_pixel_user(x, y);
         let username = match self.users.get(&user_id) {
             Some(user) => user.username.clone(),
             None => "No username".to_string(),
@@ -331,11 +569,30 @@ impl AppState {
         &self.email_regex
     }
 
-    pub fn cooldown(&self) -> u16 {
-        self.cooldown
+    /// Checks an email a user wants to use for their account, at signup or when changing it
+    pub fn check_new_email(&self, email: &str) -> Result<(), EmailError> {
+        if !self.email_regex.is_match(email) {
+            return Err(EmailError::InvalidFormat);
+        }
+
+        self.email_domains.check(email)
+    }
+
+    pub fn jwt_keys(&self) -> &JwtKeys {
+        &self.jwt_keys
+    }
+
+    /// Applies the roles saved in the database, such as those set from the command line
+    pub fn set_roles(&mut self, roles: &HashMap<u16, Role>) {
+        for (user_id, user) in self.users.iter_mut() {
+            if let Some(role) = roles.get(user_id) {
+                user.role = *role;
+            }
+        }
     }
 
-    pub fn jwt_secret(&self) -> &str {
-        &self.jwt_secret
+    /// Swaps in the keys of a rotation, see `JwtKeys::from_env`
+    pub fn set_jwt_keys(&mut self, jwt_keys: JwtKeys) {
+        self.jwt_keys = jwt_keys;
     }
 }
@@ -0,0 +1,929 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use actix::{Addr, Handler, Message};
+use chrono::Utc;
+use image::{ImageBuffer, Rgb};
+use serde_derive::Serialize;
+
+use crate::database::{blank_color, CanvasInfo, Database, DatabaseUpdate};
+use crate::models::appstate::AppStateError;
+use crate::models::event::{EventSchedule, EventState, EventStatus};
+use crate::models::region::{ProtectedRegion, RegionSummary};
+use crate::models::snapshot::Snapshot;
+use crate::models::tiles::{Tile, TileCache, TileLayout, TILE_SIZE};
+use crate::models::utils::{hex_to_rgb, parse_palette, MAX_PALETTE_SIZE};
+use crate::websocket::{CloseMessage, MessageUpdate, PlaceWebSocketConnection, ServerMessage};
+
+pub const DEFAULT_CANVAS: &str = "main";
+/// Least time between two encodings of the full PNG, clients get the placements after it from
+/// `/api/updates` and the WebSocket
+const PNG_INTERVAL_SEC: i64 = 10;
+
+#[derive(Serialize)]
+pub struct CanvasSummary {
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub cooldown: u16,
+    pub palette: Vec<String>,
+    pub retired_colors: Vec<u8>,
+}
+
+#[derive(Serialize)]
+pub struct PaletteInfo<'a> {
+    pub colors: &'a [String],
+    pub retired: &'a [u8],
+}
+
+pub struct Canvas {
+    id: u32,
+    name: String,
+    width: usize,
+    height: usize,
+    pixels_color: Vec<u8>,
+    pixels_user: Vec<u16>,
+    colors: Vec<String>,
+    palette: Vec<(u8, u8, u8)>,
+    retired_colors: Vec<u8>,
+    blank_color: u8,
+    cooldown: u16,
+    cooldowns: HashMap<u16, i64>,
+    regions: Vec<ProtectedRegion>,
+    schedule: EventSchedule,
+    event_state: EventState,
+    countdown_time: i64,
+    /// The event closed and its final snapshot is not saved yet
+    close_snapshot_due: bool,
+    png: Vec<u8>,
+    tiles: TileCache,
+    seq: u64,
+    png_seq: u64,
+    png_time: i64,
+    flush_seq: u64,
+    log_start: u64,
+    snapshot_dirty: bool,
+    snapshot_time: i64,
+    database_updates: Vec<DatabaseUpdate>,
+    message_updates: Vec<MessageUpdate>,
+    sessions: RwLock<Vec<Addr<PlaceWebSocketConnection>>>,
+}
+
+impl Canvas {
+    /// Rebuilds a canvas from its newest snapshot in `snapshot_dir` and the placements made
+    /// after it, or from every saved pixel without one. `seq` is the last pixel id of every canvas
+    pub fn load(
+        info: CanvasInfo,
+        cooldown: u16,
+        colors: Vec<String>,
+        seq: u64,
+        snapshot_dir: Option<&Path>,
+        db: &Database,
+    ) -> Result<Self, AppStateError> {
+        let palette = parse_palette(&colors)?;
+        let retired_colors: Vec<u8> = match &info.retired_colors {
+            Some(retired) => serde_json::from_str(retired)?,
+            None => Vec::new(),
+        };
+        // Fixed when the canvas is first loaded, so that colors added later keep its blank pixels
+        let blank = match info.blank_color {
+            Some(color) => color,
+            None => {
+                let color = blank_color(palette.len());
+                db.save_canvas_blank_color(info.canvas_id, color)
+                    .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+                color
+            }
+        };
+
+        let snapshot = snapshot_dir
+            .and_then(|dir| {
+                Snapshot::load(dir, info.canvas_id)
+                    .map_err(|err| eprintln!("Ignoring snapshot of {}: {}", info.name, err))
+                    .ok()
+                    .flatten()
+            })
+            .filter(|snapshot| {
+                (snapshot.width, snapshot.height) == (info.width, info.height)
+                    && snapshot.seq <= seq
+            });
+
+        let (pixels_color, pixels_user, snapshot_dirty) = match snapshot {
+            Some(snapshot) => {
+                let updates = db
+                    .get_pixels_after(info.canvas_id, snapshot.seq)
+                    .map_err(|e| AppStateError::PixelFetchError(e.to_string()))?;
+
+                let mut pixels_color = snapshot.pixels_color;
+                let mut pixels_user = snapshot.pixels_user;
+                for update in &updates {
+                    if update.x < info.width && update.y < info.height {
+                        let index = update.x * info.height + update.y;
+                        pixels_color[index] = update.color;
+                        pixels_user[index] = update.user_id;
+                    }
+                }
+                (pixels_color, pixels_user, !updates.is_empty())
+            }
+            None => {
+                let (pixels_color, pixels_user) = db
+                    .get_pixels(info.canvas_id, info.width, info.height, blank)
+                    .map_err(|e| AppStateError::PixelFetchError(e.to_string()))?;
+                (pixels_color, pixels_user, true)
+            }
+        };
+
+        let regions = db
+            .get_regions(info.canvas_id)
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?
+            .into_iter()
+            .map(ProtectedRegion::load)
+            .collect();
+
+        let schedule = info.schedule;
+        let close_snapshot = info.close_snapshot;
+
+        let mut canvas = Self {
+            id: info.canvas_id,
+            name: info.name,
+            width: info.width,
+            height: info.height,
+            pixels_color,
+            pixels_user,
+            colors,
+            palette,
+            retired_colors,
+            blank_color: blank,
+            cooldown: info.cooldown.unwrap_or(cooldown),
+            cooldowns: HashMap::new(),
+            regions,
+            schedule,
+            event_state: schedule.state_at(Utc::now().timestamp()),
+            countdown_time: 0,
+            close_snapshot_due: false,
+            png: Vec::new(),
+            tiles: TileCache::new(info.width, info.height),
+            seq,
+            png_seq: seq,
+            png_time: 0,
+            flush_seq: seq,
+            log_start: seq,
+            snapshot_dirty,
+            snapshot_time: 0,
+            database_updates: Vec::new(),
+            message_updates: Vec::new(),
+            sessions: RwLock::new(Vec::new()),
+        };
+        canvas.refresh_png()?;
+        if let Some(dir) = snapshot_dir {
+            canvas.take_missed_close_snapshot(dir, close_snapshot, db);
+        }
+
+        Ok(canvas)
+    }
+
+    /// Takes the final snapshot of an event that closed while the server was down, or leaves
+    /// it to the next flush if it fails
+    fn take_missed_close_snapshot(&mut self, dir: &Path, taken: Option<i64>, db: &Database) {
+        let closes_at = match self.schedule.closes_at {
+            Some(closes_at) if self.event_state == EventState::Closed => closes_at,
+            _ => return,
+        };
+        if taken == Some(closes_at) {
+            return;
+        }
+
+        let snapshot = Snapshot {
+            seq: self.seq,
+            width: self.width,
+            height: self.height,
+            pixels_color: self.pixels_color.clone(),
+            pixels_user: self.pixels_user.clone(),
+        };
+        let saved = snapshot
+            .save(dir, self.id)
+            .map_err(|err| err.to_string())
+            .and_then(|_| {
+                db.save_close_snapshot(self.id, closes_at)
+                    .map_err(|err| err.to_string())
+            });
+
+        match saved {
+            Ok(()) => {
+                println!("Saved the final snapshot of {}", self.name);
+                self.snapshot_dirty = false;
+                self.snapshot_time = Utc::now().timestamp();
+            }
+            Err(err) => {
+                eprintln!("Error saving the final snapshot of {}: {}", self.name, err);
+                self.close_snapshot_due = true;
+                self.snapshot_dirty = true;
+                self.snapshot_time = 0;
+            }
+        }
+    }
+
+    pub fn draw(
+        &mut self,
+        x: usize,
+        y: usize,
+        user_id: u16,
+        color: u8,
+        seq: u64,
+    ) -> Result<(), AppStateError> {
+        if x >= self.width || y >= self.height {
+            return Err(AppStateError::InvalidValueError(
+                "x or y out of bounds".to_string(),
+            ));
+        }
+        if color as usize >= self.palette.len() {
+            return Err(AppStateError::InvalidValueError(
+                "color not in palette".to_string(),
+            ));
+        }
+        if self.is_retired(color) {
+            return Err(AppStateError::InvalidValueError(
+                "color retired".to_string(),
+            ));
+        }
+
+        let time = Utc::now().timestamp();
+        self.cooldowns.insert(user_id, time + self.cooldown as i64);
+
+        self.place(x, y, user_id, color, seq, false)
+    }
+
+    /// Puts back a placement covered by a rolled back one. Restored placements are saved and
+    /// broadcast like any other, but do not start a cooldown
+    pub fn restore(
+        &mut self,
+        x: usize,
+        y: usize,
+        user_id: u16,
+        color: u8,
+        seq: u64,
+    ) -> Result<(), AppStateError> {
+        if x >= self.width || y >= self.height {
+            return Err(AppStateError::InvalidValueError(
+                "x or y out of bounds".to_string(),
+            ));
+        }
+
+        self.place(x, y, user_id, color, seq, true)
+    }
+
+    fn place(
+        &mut self,
+        x: usize,
+        y: usize,
+        user_id: u16,
+        color: u8,
+        seq: u64,
+        restored: bool,
+    ) -> Result<(), AppStateError> {
+        let index = x * self.height + y;
+        self.pixels_user[index] = user_id;
+        self.pixels_color[index] = color;
+        self.tiles.mark_dirty(x, y);
+        self.snapshot_dirty = true;
+
+        self.seq = seq;
+        self.database_updates.push(DatabaseUpdate {
+            seq,
+            canvas_id: self.id,
+            x,
+            y,
+            color,
+            user_id,
+            timestamp: Utc::now().timestamp(),
+            restored,
+        });
+        let message_update = MessageUpdate { seq, x, y, color };
+        self.message_updates.push(message_update);
+        self.broadcast(message_update)
+    }
+
+    /// Placements not saved to the database yet
+    pub fn pending_updates(&self) -> &[DatabaseUpdate] {
+        &self.database_updates
+    }
+
+    /// Grows the canvas, keeping every pixel at its coordinates
+    pub fn resize(
+        &mut self,
+        width: usize,
+        height: usize,
+        db: &Database,
+    ) -> Result<(), AppStateError> {
+        if width < self.width || height < self.height {
+            return Err(AppStateError::InvalidValueError(
+                "the canvas can only grow".to_string(),
+            ));
+        }
+
+        db.save_canvas_size(self.id, width, height)
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+
+        let mut pixels_color = vec![self.blank_color(); width * height];
+        let mut pixels_user = vec![0; width * height];
+        for x in 0..self.width {
+            let old_column = x * self.height;
+            let new_column = x * height;
+            pixels_color[new_column..new_column + self.height]
+                .copy_from_slice(&self.pixels_color[old_column..old_column + self.height]);
+            pixels_user[new_column..new_column + self.height]
+                .copy_from_slice(&self.pixels_user[old_column..old_column + self.height]);
+        }
+
+        self.width = width;
+        self.height = height;
+        self.pixels_color = pixels_color;
+        self.pixels_user = pixels_user;
+        self.tiles = TileCache::new(width, height);
+        self.png.clear();
+        self.snapshot_dirty = true;
+        self.snapshot_time = 0;
+
+        self.broadcast(ServerMessage::Resize {
+            width,
+            height,
+            blank: self.blank_color,
+        })
+    }
+
+    /// Captures what the next flush has to save and encode. The returned job runs without the
+    /// app state lock and hands its result back to `finish_flush`. A snapshot is included when
+    /// the canvas changed and the last one is older than `snapshot`'s interval
+    pub fn begin_flush(&mut self, snapshot: Option<(&Path, i64)>) -> FlushJob {
+        let time = Utc::now().timestamp();
+        let snapshot_dir = snapshot
+            .filter(|(_, interval)| self.snapshot_dirty && time - self.snapshot_time >= *interval)
+            .map(|(dir, _)| dir.to_path_buf());
+        if snapshot_dir.is_some() {
+            self.snapshot_dirty = false;
+            self.snapshot_time = time;
+        }
+
+        let encode_png = self.png.is_empty()
+            || (self.png_seq < self.seq && time - self.png_time >= PNG_INTERVAL_SEC);
+        if encode_png {
+            self.png_time = time;
+        }
+        let dirty_tiles = self.tiles.take_dirty_tiles();
+
+        // The pixels are only copied when something is encoded or saved from them
+        let pixels_color = if encode_png || !dirty_tiles.is_empty() || snapshot_dir.is_some() {
+            self.pixels_color.clone()
+        } else {
+            Vec::new()
+        };
+
+        let close_snapshot = snapshot_dir
+            .as_ref()
+            .filter(|_| self.close_snapshot_due && self.event_state == EventState::Closed)
+            .and(self.schedule.closes_at);
+
+        FlushJob {
+            canvas: self.name.clone(),
+            id: self.id,
+            seq: self.seq,
+            width: self.width,
+            height: self.height,
+            palette: self.palette.clone(),
+            pixels_color,
+            pixels_user: snapshot_dir.as_ref().map(|_| self.pixels_user.clone()),
+            dirty_tiles,
+            database_updates: std::mem::take(&mut self.database_updates),
+            encode_png,
+            snapshot_dir,
+            close_snapshot,
+        }
+    }
+
+    /// Installs the tiles and PNG encoded by a flush job, keeping the placements it failed to
+    /// save for the next one. Encodings are dropped if the canvas was resized in the meantime
+    pub fn finish_flush(&mut self, result: FlushResult) {
+        if !result.unsaved_updates.is_empty() {
+            let newer = std::mem::replace(&mut self.database_updates, result.unsaved_updates);
+            self.database_updates.extend(newer);
+        }
+        if result.snapshot_failed {
+            self.snapshot_dirty = true;
+        }
+        if result.close_snapshot_saved {
+            self.close_snapshot_due = false;
+        }
+
+        if result.size != (self.width, self.height) {
+            return;
+        }
+
+        for (tx, ty, png) in result.tiles {
+            self.tiles.set(tx, ty, png);
+        }
+        for (tx, ty) in result.failed_tiles {
+            self.tiles.mark_dirty(tx * TILE_SIZE, ty * TILE_SIZE);
+        }
+        if let Some(png) = result.png {
+            self.png = png;
+            self.png_seq = result.seq;
+        }
+
+        // Clients fetching the PNG catch up from its sequence, which can be several flushes old
+        self.log_start = self.flush_seq.min(self.png_seq);
+        self.flush_seq = result.seq;
+        let log_start = self.log_start;
+        self.message_updates.retain(|update| update.seq > log_start);
+    }
+
+    /// Encodes the PNG right away, so that it is served before the next flush
+    fn refresh_png(&mut self) -> Result<(), AppStateError> {
+        self.png = self.encode_png(&self.pixels_color)?;
+        self.png_seq = self.seq;
+        self.png_time = Utc::now().timestamp();
+        Ok(())
+    }
+
+    pub fn encode_png(&self, pixels_color: &[u8]) -> Result<Vec<u8>, AppStateError> {
+        encode_canvas(&self.palette, pixels_color, self.width, self.height)
+    }
+
+    pub fn add_session(
+        &self,
+        session: Addr<PlaceWebSocketConnection>,
+    ) -> Result<(), AppStateError> {
+        let time = Utc::now().timestamp();
+        session.do_send(self.state_message(time));
+        if let Some(countdown) = self.countdown_message(time) {
+            session.do_send(countdown);
+        }
+
+        self.sessions
+            .write()
+            .map(|mut sessions| sessions.push(session))
+            .map_err(|_| AppStateError::SessionAddError)
+    }
+
+    pub fn remove_session(
+        &self,
+        session: &Addr<PlaceWebSocketConnection>,
+    ) -> Result<(), AppStateError> {
+        self.sessions
+            .write()
+            .map(|mut sessions| sessions.retain(|other| other != session))
+            .map_err(|_| AppStateError::SessionRemoveError)
+    }
+
+    pub fn close_sessions(&self, reason: &str) -> Result<(), AppStateError> {
+        self.broadcast(CloseMessage(reason.to_string()))
+    }
+
+    fn broadcast<M>(&self, msg: M) -> Result<(), AppStateError>
+    where
+        M: Message<Result = ()> + Clone + Send + 'static,
+        PlaceWebSocketConnection: Handler<M>,
+    {
+        let sessions = self
+            .sessions
+            .read()
+            .map_err(|_| AppStateError::SessionAddError)?;
+        for session in sessions.iter().filter(|session| session.connected()) {
+            session.do_send(msg.clone());
+        }
+        Ok(())
+    }
+
+    /// Updates with a sequence number above `since`, or above the PNG's one if `None`.
+    /// Only the updates of the current and previous flush, and those after the PNG, are kept.
+    pub fn get_message_updates(
+        &self,
+        since: Option<u64>,
+    ) -> Result<Vec<MessageUpdate>, AppStateError> {
+        let since = since.unwrap_or(self.png_seq);
+        if since < self.log_start {
+            return Err(AppStateError::SequenceExpiredError);
+        }
+
+        Ok(self
+            .message_updates
+            .iter()
+            .filter(|update| update.seq > since)
+            .copied()
+            .collect())
+    }
+
+    pub fn summary(&self) -> CanvasSummary {
+        CanvasSummary {
+            name: self.name.clone(),
+            width: self.width,
+            height: self.height,
+            cooldown: self.cooldown,
+            palette: self.colors.clone(),
+            retired_colors: self.retired_colors.clone(),
+        }
+    }
+
+    pub fn get_palette_info(&self) -> PaletteInfo<'_> {
+        PaletteInfo {
+            colors: &self.colors,
+            retired: &self.retired_colors,
+        }
+    }
+
+    pub fn is_retired(&self, color: u8) -> bool {
+        self.retired_colors.contains(&color)
+    }
+
+    /// Appends a color to the palette, returning its index
+    pub fn add_color(&mut self, color: &str, db: &Database) -> Result<u8, AppStateError> {
+        if self.colors.len() >= MAX_PALETTE_SIZE {
+            return Err(AppStateError::InvalidValueError(format!(
+                "the palette already has {} colors",
+                MAX_PALETTE_SIZE
+            )));
+        }
+        let rgb = hex_to_rgb(color).ok_or_else(|| {
+            AppStateError::InvalidValueError(format!("{} is not a #rrggbb color", color))
+        })?;
+
+        let mut colors = self.colors.clone();
+        colors.push(format!("#{:02x}{:02x}{:02x}", rgb.0, rgb.1, rgb.2));
+        self.save_palette(&colors, &self.retired_colors, db)?;
+
+        self.colors = colors;
+        self.palette.push(rgb);
+        self.broadcast_palette()?;
+
+        Ok((self.colors.len() - 1) as u8)
+    }
+
+    /// Retires a color, or brings it back. Pixels keep their retired colors, but new
+    /// placements cannot use them
+    pub fn set_retired(
+        &mut self,
+        color: u8,
+        retired: bool,
+        db: &Database,
+    ) -> Result<(), AppStateError> {
+        if color as usize >= self.colors.len() {
+            return Err(AppStateError::InvalidValueError(
+                "color not in palette".to_string(),
+            ));
+        }
+
+        let mut retired_colors = self.retired_colors.clone();
+        retired_colors.retain(|&other| other != color);
+        if retired {
+            retired_colors.push(color);
+            retired_colors.sort_unstable();
+        }
+        self.save_palette(&self.colors, &retired_colors, db)?;
+
+        self.retired_colors = retired_colors;
+        self.broadcast_palette()
+    }
+
+    fn save_palette(
+        &self,
+        colors: &[String],
+        retired_colors: &[u8],
+        db: &Database,
+    ) -> Result<(), AppStateError> {
+        db.save_canvas_palette(
+            self.id,
+            &serde_json::to_string(colors)?,
+            &serde_json::to_string(retired_colors)?,
+        )
+        .map_err(|e| AppStateError::DatabaseError(e.to_string()))
+    }
+
+    fn broadcast_palette(&self) -> Result<(), AppStateError> {
+        self.broadcast(ServerMessage::Palette {
+            colors: self.colors.clone(),
+            retired: self.retired_colors.clone(),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    pub fn event_state(&self, time: i64) -> EventState {
+        self.schedule.state_at(time)
+    }
+
+    pub fn schedule(&self) -> EventSchedule {
+        self.schedule
+    }
+
+    pub fn event_status(&self, time: i64) -> EventStatus {
+        self.schedule.status_at(time)
+    }
+
+    /// Saves a new schedule and tells the clients if it changed the state right away.
+    /// Returns `true` if it closed the event
+    pub fn set_schedule(
+        &mut self,
+        schedule: EventSchedule,
+        db: &Database,
+    ) -> Result<bool, AppStateError> {
+        db.save_canvas_schedule(self.id, &schedule)
+            .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+        self.schedule = schedule;
+        self.countdown_time = 0;
+
+        self.update_event(Utc::now().timestamp())
+    }
+
+    /// Broadcasts the state of the event when it changed since the last call, and a countdown
+    /// to the next change every minute, then every second for the last ten. A closing event
+    /// gets a snapshot on the next flush. Returns `true` if the event just closed
+    pub fn update_event(&mut self, time: i64) -> Result<bool, AppStateError> {
+        let state = self.schedule.state_at(time);
+        let changed = state != self.event_state;
+        if changed {
+            self.event_state = state;
+            self.broadcast(self.state_message(time))?;
+        }
+
+        if self.schedule.next_change(time).is_some() && (changed || self.countdown_due(time)) {
+            self.countdown_time = time;
+            if let Some(countdown) = self.countdown_message(time) {
+                self.broadcast(countdown)?;
+            }
+        }
+
+        let closed = changed && state == EventState::Closed;
+        if changed {
+            self.close_snapshot_due = closed;
+        }
+        if closed {
+            self.snapshot_dirty = true;
+            self.snapshot_time = 0;
+        }
+
+        Ok(closed)
+    }
+
+    /// Whether `update_event` would change the state or send a countdown at `time`
+    pub fn event_update_due(&self, time: i64) -> bool {
+        self.schedule.state_at(time) != self.event_state || self.countdown_due(time)
+    }
+
+    fn countdown_due(&self, time: i64) -> bool {
+        self.schedule
+            .next_change(time)
+            .is_some_and(|(next_change, _)| {
+                (next_change - time <= 10 || time - self.countdown_time >= 60)
+                    && time != self.countdown_time
+            })
+    }
+
+    fn state_message(&self, time: i64) -> ServerMessage {
+        ServerMessage::State {
+            state: self.schedule.state_at(time),
+            opens_at: self.schedule.opens_at,
+            closes_at: self.schedule.closes_at,
+        }
+    }
+
+    fn countdown_message(&self, time: i64) -> Option<ServerMessage> {
+        self.schedule
+            .next_change(time)
+            .map(|(next_change, next_state)| ServerMessage::Countdown {
+                state: next_state,
+                seconds: next_change - time,
+            })
+    }
+
+    /// First protected region locking the pixel at `x`, `y`
+    pub fn region_at(&self, x: usize, y: usize) -> Option<&ProtectedRegion> {
+        self.regions.iter().find(|region| region.contains(x, y))
+    }
+
+    pub fn get_regions(&self) -> Vec<RegionSummary<'_>> {
+        self.regions.iter().map(ProtectedRegion::summary).collect()
+    }
+
+    pub fn add_region(&mut self, region: ProtectedRegion) {
+        self.regions.push(region);
+    }
+
+    /// Removes a region, `false` if the canvas has none with this id
+    pub fn remove_region(&mut self, region_id: u32) -> bool {
+        let len = self.regions.len();
+        self.regions.retain(|region| region.id() != region_id);
+        self.regions.len() != len
+    }
+
+    pub fn blank_color(&self) -> u8 {
+        self.blank_color
+    }
+
+    pub fn get_palette(&self) -> &Vec<(u8, u8, u8)> {
+        &self.palette
+    }
+
+    pub fn get_png(&self) -> &Vec<u8> {
+        &self.png
+    }
+
+    pub fn get_png_seq(&self) -> u64 {
+        self.png_seq
+    }
+
+    pub fn get_tile(&self, tx: usize, ty: usize) -> Option<&Tile> {
+        self.tiles.get(tx, ty)
+    }
+
+    pub fn get_tile_layout(&self) -> TileLayout {
+        self.tiles.layout()
+    }
+
+    pub fn get_flush_seq(&self) -> u64 {
+        self.flush_seq
+    }
+
+    pub fn get_seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn get_users_connected(&self) -> usize {
+        self.sessions.read().unwrap().len()
+    }
+
+    pub fn get_pixel_user(&self, x: usize, y: usize) -> u16 {
+        self.pixels_user[x * self.height + y]
+    }
+
+    /// Timestamp at which `user_id` can draw again on this canvas
+    pub fn user_cooldown(&self, user_id: u16) -> i64 {
+        self.cooldowns.get(&user_id).copied().unwrap_or(0)
+    }
+
+    pub fn cooldown(&self) -> u16 {
+        self.cooldown
+    }
+}
+
+/// Canvas state captured by `Canvas::begin_flush`
+pub struct FlushJob {
+    canvas: String,
+    id: u32,
+    seq: u64,
+    width: usize,
+    height: usize,
+    palette: Vec<(u8, u8, u8)>,
+    pixels_color: Vec<u8>,
+    pixels_user: Option<Vec<u16>>,
+    dirty_tiles: Vec<(usize, usize)>,
+    database_updates: Vec<DatabaseUpdate>,
+    encode_png: bool,
+    snapshot_dir: Option<PathBuf>,
+    /// `closes_at` of the event whose final snapshot this job takes
+    close_snapshot: Option<i64>,
+}
+
+pub struct FlushResult {
+    canvas: String,
+    seq: u64,
+    size: (usize, usize),
+    tiles: Vec<(usize, usize, Vec<u8>)>,
+    failed_tiles: Vec<(usize, usize)>,
+    png: Option<Vec<u8>>,
+    unsaved_updates: Vec<DatabaseUpdate>,
+    snapshot_failed: bool,
+    close_snapshot_saved: bool,
+}
+
+impl FlushJob {
+    /// Saves the placements, encodes the dirty tiles and the PNG, then writes the snapshot
+    /// if every placement it covers was saved
+    pub fn run(self, db: &Database) -> FlushResult {
+        let unsaved_updates = match db.save_pixel_updates(&self.database_updates) {
+            Ok(()) => Vec::new(),
+            Err(err) => {
+                eprintln!("Error saving pixel updates: {}", err);
+                self.database_updates
+            }
+        };
+
+        let mut tiles = Vec::new();
+        let mut failed_tiles = Vec::new();
+        for &(tx, ty) in &self.dirty_tiles {
+            let x = tx * TILE_SIZE;
+            let y = ty * TILE_SIZE;
+            let region = (
+                x,
+                y,
+                TILE_SIZE.min(self.width - x),
+                TILE_SIZE.min(self.height - y),
+            );
+            match encode_region(&self.palette, &self.pixels_color, self.height, region) {
+                Ok(png) => tiles.push((tx, ty, png)),
+                Err(err) => {
+                    eprintln!("Error encoding tile {},{}: {}", tx, ty, err);
+                    failed_tiles.push((tx, ty));
+                }
+            }
+        }
+
+        let png = if self.encode_png {
+            let region = (0, 0, self.width, self.height);
+            encode_region(&self.palette, &self.pixels_color, self.height, region)
+                .map_err(|err| eprintln!("Error encoding {}: {}", self.canvas, err))
+                .ok()
+        } else {
+            None
+        };
+
+        let snapshot_taken = self.snapshot_dir.is_some();
+        let snapshot_failed = match (self.snapshot_dir, self.pixels_user) {
+            (Some(dir), Some(pixels_user)) => {
+                !unsaved_updates.is_empty()
+                    || Snapshot {
+                        seq: self.seq,
+                        width: self.width,
+                        height: self.height,
+                        pixels_color: self.pixels_color,
+                        pixels_user,
+                    }
+                    .save(&dir, self.id)
+                    .map_err(|err| eprintln!("Error saving snapshot: {}", err))
+                    .is_err()
+            }
+            _ => false,
+        };
+
+        let close_snapshot_saved = match self.close_snapshot {
+            Some(closes_at) if snapshot_taken && !snapshot_failed => db
+                .save_close_snapshot(self.id, closes_at)
+                .map_err(|err| eprintln!("Error saving close snapshot: {}", err))
+                .is_ok(),
+            _ => false,
+        };
+
+        FlushResult {
+            canvas: self.canvas,
+            seq: self.seq,
+            size: (self.width, self.height),
+            tiles,
+            failed_tiles,
+            png,
+            unsaved_updates,
+            snapshot_failed,
+            close_snapshot_saved,
+        }
+    }
+}
+
+impl FlushResult {
+    pub fn canvas(&self) -> &str {
+        &self.canvas
+    }
+}
+
+/// Encodes a whole canvas, for pixels that are not the current ones of a `Canvas`
+pub fn encode_canvas(
+    palette: &[(u8, u8, u8)],
+    pixels_color: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<u8>, AppStateError> {
+    encode_region(palette, pixels_color, height, (0, 0, width, height))
+}
+
+/// Encodes the `(left, top, width, height)` region of a canvas `canvas_height` pixels high
+fn encode_region(
+    palette: &[(u8, u8, u8)],
+    pixels_color: &[u8],
+    canvas_height: usize,
+    (left, top, width, height): (usize, usize, usize, usize),
+) -> Result<Vec<u8>, AppStateError> {
+    let image = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+        let index = (left + x as usize) * canvas_height + (top + y as usize);
+        let color = palette[pixels_color[index] as usize];
+        Rgb([color.0, color.1, color.2])
+    });
+
+    let mut png: Vec<u8> = Vec::new();
+    {
+        let mut cursor = std::io::Cursor::new(&mut png);
+        image
+            .write_to(&mut cursor, image::ImageOutputFormat::Png)
+            .map_err(|_| {
+                AppStateError::FileReadError(std::io::Error::other("Error writing image"))
+            })?;
+    }
+
+    Ok(png)
+}
@@ -0,0 +1,111 @@
+use std::env;
+use std::fmt;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EmailError {
+    #[error("Invalid email format")]
+    InvalidFormat,
+    #[error("emails from {0} are not allowed, use one from {1}")]
+    DomainNotAllowed(String, String),
+    #[error("emails from {0} are blocked")]
+    DomainBlocked(String),
+    #[error("Invalid domain {1} in {0}")]
+    InvalidPattern(String, String),
+}
+
+/// A domain, or with a leading `*.` any of its subdomains but not the domain itself
+enum DomainPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+impl DomainPattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let pattern = pattern.to_lowercase();
+        let (pattern, domain) = match pattern.strip_prefix("*.") {
+            Some(domain) => (Self::Subdomains(domain.to_string()), domain),
+            None => (Self::Exact(pattern.clone()), pattern.as_str()),
+        };
+
+        let valid = domain.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+        valid.then_some(pattern)
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        match self {
+            Self::Exact(exact) => domain == exact,
+            Self::Subdomains(parent) => domain
+                .strip_suffix(parent.as_str())
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+        }
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Exact(domain) => write!(f, "{}", domain),
+            Self::Subdomains(domain) => write!(f, "*.{}", domain),
+        }
+    }
+}
+
+/// Email domains users can sign up with, from the comma separated `EMAIL_ALLOWED_DOMAINS` and
+/// `EMAIL_BLOCKED_DOMAINS`. Every domain is allowed when no allowed domain is set, and blocked
+/// domains win over allowed ones
+pub struct EmailDomains {
+    allowed: Vec<DomainPattern>,
+    blocked: Vec<DomainPattern>,
+}
+
+fn patterns_from_env(name: &str) -> Result<Vec<DomainPattern>, EmailError> {
+    let Ok(list) = env::var(name) else {
+        return Ok(Vec::new());
+    };
+
+    list.split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            DomainPattern::parse(pattern)
+                .ok_or_else(|| EmailError::InvalidPattern(name.to_string(), pattern.to_string()))
+        })
+        .collect()
+}
+
+impl EmailDomains {
+    pub fn from_env() -> Result<Self, EmailError> {
+        Ok(Self {
+            allowed: patterns_from_env("EMAIL_ALLOWED_DOMAINS")?,
+            blocked: patterns_from_env("EMAIL_BLOCKED_DOMAINS")?,
+        })
+    }
+
+    pub fn check(&self, email: &str) -> Result<(), EmailError> {
+        let domain = email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+            .ok_or(EmailError::InvalidFormat)?;
+
+        if self.blocked.iter().any(|pattern| pattern.matches(&domain)) {
+            return Err(EmailError::DomainBlocked(domain));
+        }
+
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|pattern| pattern.matches(&domain))
+        {
+            let allowed = self
+                .allowed
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(EmailError::DomainNotAllowed(domain, allowed));
+        }
+
+        Ok(())
+    }
+}
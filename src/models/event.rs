@@ -0,0 +1,72 @@
+use serde_derive::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventState {
+    /// Before the opening time
+    Pending,
+    Open,
+    /// Open, but stopped by an admin
+    Frozen,
+    /// After the closing time
+    Closed,
+}
+
+/// When a canvas accepts placements. A canvas without opening or closing time is open
+/// from or until forever
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct EventSchedule {
+    pub opens_at: Option<i64>,
+    pub closes_at: Option<i64>,
+    pub frozen: bool,
+}
+
+#[derive(Serialize)]
+pub struct EventStatus {
+    pub state: EventState,
+    #[serde(flatten)]
+    pub schedule: EventSchedule,
+    pub now: i64,
+    /// Time of the next scheduled change of state, and the state it leads to
+    pub next_change: Option<i64>,
+    pub next_state: Option<EventState>,
+}
+
+impl EventSchedule {
+    pub fn state_at(&self, time: i64) -> EventState {
+        if self.closes_at.is_some_and(|closes_at| time >= closes_at) {
+            EventState::Closed
+        } else if self.opens_at.is_some_and(|opens_at| time < opens_at) {
+            EventState::Pending
+        } else if self.frozen {
+            EventState::Frozen
+        } else {
+            EventState::Open
+        }
+    }
+
+    /// Next opening or closing after `time`, with the state the canvas will then be in
+    pub fn next_change(&self, time: i64) -> Option<(i64, EventState)> {
+        match self.state_at(time) {
+            EventState::Pending => {
+                let opens_at = self.opens_at?;
+                Some((opens_at, self.state_at(opens_at)))
+            }
+            EventState::Open | EventState::Frozen => self
+                .closes_at
+                .map(|closes_at| (closes_at, EventState::Closed)),
+            EventState::Closed => None,
+        }
+    }
+
+    pub fn status_at(&self, time: i64) -> EventStatus {
+        let next_change = self.next_change(time);
+        EventStatus {
+            state: self.state_at(time),
+            schedule: *self,
+            now: time,
+            next_change: next_change.map(|(at, _)| at),
+            next_state: next_change.map(|(_, state)| state),
+        }
+    }
+}
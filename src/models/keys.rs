@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::{env, fs};
+
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+/// Key id of `JWT_SECRET`, also used for tokens issued without a `kid`
+pub const DEFAULT_KID: &str = "default";
+
+#[derive(Error, Debug)]
+pub enum KeysError {
+    #[error("Error reading JWT_KEYS_PATH: {0}")]
+    FileRead(#[from] std::io::Error),
+    #[error("Error parsing JWT_KEYS_PATH: {0}")]
+    JsonParse(#[from] serde_json::Error),
+    #[error("No JWT key, set JWT_SECRET or JWT_KEYS_PATH")]
+    NoKey,
+    #[error("Empty JWT key {0}")]
+    EmptyKey(String),
+    #[error("Current JWT key {0} is not in the keys")]
+    NoCurrentKey(String),
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    current: String,
+    keys: HashMap<String, String>,
+}
+
+/// Keys that sign and verify the JWTs, by key id. Tokens are signed with the current key and
+/// verified with the key named by their `kid`, so older keys keep working until they are
+/// removed
+pub struct JwtKeys {
+    current: String,
+    keys: HashMap<String, String>,
+}
+
+impl JwtKeys {
+    /// Reads the keys of the `JWT_KEYS_PATH` JSON file, `{"current": kid, "keys": {kid: secret}}`,
+    /// which are then the only accepted keys. Without a file, `JWT_SECRET` is the `default` key
+    pub fn from_env() -> Result<Self, KeysError> {
+        let (current, keys) = match env::var("JWT_KEYS_PATH") {
+            Ok(path) if !path.is_empty() => {
+                let file: KeysFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+                (file.current, file.keys)
+            }
+            _ => {
+                let keys = env::var("JWT_SECRET")
+                    .ok()
+                    .filter(|key| !key.is_empty())
+                    .map(|secret| (DEFAULT_KID.to_string(), secret))
+                    .into_iter()
+                    .collect();
+                (DEFAULT_KID.to_string(), keys)
+            }
+        };
+
+        if keys.is_empty() {
+            return Err(KeysError::NoKey);
+        }
+        if let Some((kid, _)) = keys.iter().find(|(_, key)| key.is_empty()) {
+            return Err(KeysError::EmptyKey(kid.clone()));
+        }
+        if !keys.contains_key(&current) {
+            return Err(KeysError::NoCurrentKey(current));
+        }
+
+        Ok(Self { current, keys })
+    }
+
+    /// Id and key to sign new tokens with
+    pub fn encoding_key(&self) -> (&str, EncodingKey) {
+        let key = &self.keys[&self.current];
+        (&self.current, EncodingKey::from_secret(key.as_bytes()))
+    }
+
+    /// Key to verify a token signed with `kid`, `None` once it is retired
+    pub fn decoding_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        self.keys
+            .get(kid.unwrap_or(DEFAULT_KID))
+            .map(|key| DecodingKey::from_secret(key.as_bytes()))
+    }
+
+    /// Whether tokens signed with `kid` are still accepted
+    pub fn has_key(&self, kid: Option<&str>) -> bool {
+        self.keys.contains_key(kid.unwrap_or(DEFAULT_KID))
+    }
+
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.keys.len()
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, io, process};
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rand::Rng;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum MailerError {
+    #[error("Environment variable not set: {0}")]
+    EnvVarNotSet(String),
+    #[error("Invalid mailer configuration: {0}")]
+    ConfigError(String),
+    #[error("Invalid email address: {0}")]
+    AddressError(#[from] lettre::address::AddressError),
+    #[error("Error building email: {0}")]
+    BuildError(#[from] lettre::error::Error),
+    #[error("SMTP error: {0}")]
+    SmtpError(#[from] lettre::transport::smtp::Error),
+    #[error("Error writing email: {0}")]
+    IoError(#[from] io::Error),
+}
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers the emails sent to users
+pub trait Mailer: Send + Sync {
+    fn send(&self, email: &Email) -> Result<(), MailerError>;
+
+    /// Whether emails actually reach users. Users are verified on signup when they do not
+    fn delivers(&self) -> bool {
+        true
+    }
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, MailerError> {
+    Ok(Message::builder()
+        .from(from.clone())
+        .to(email.to.parse()?)
+        .subject(&email.subject)
+        .body(email.body.clone())?)
+}
+
+/// Sends through an SMTP relay with STARTTLS
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), MailerError> {
+        self.transport.send(&build_message(&self.from, email)?)?;
+        Ok(())
+    }
+}
+
+/// Drops each email as a file in the `new` folder of a maildir
+pub struct MaildirMailer {
+    from: Mailbox,
+    dir: PathBuf,
+}
+
+impl Mailer for MaildirMailer {
+    fn send(&self, email: &Email) -> Result<(), MailerError> {
+        let message = build_message(&self.from, email)?;
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{}.{}_{}.place-rs",
+            time.as_secs(),
+            process::id(),
+            rand::thread_rng().gen::<u32>()
+        );
+
+        for folder in ["tmp", "new", "cur"] {
+            fs::create_dir_all(self.dir.join(folder))?;
+        }
+        let tmp_path = self.dir.join("tmp").join(&name);
+        fs::write(&tmp_path, message.formatted())?;
+        fs::rename(tmp_path, self.dir.join("new").join(name))?;
+
+        Ok(())
+    }
+}
+
+/// Prints each email, for local development
+pub struct StdoutMailer {
+    from: Mailbox,
+}
+
+impl Mailer for StdoutMailer {
+    fn send(&self, email: &Email) -> Result<(), MailerError> {
+        let message = build_message(&self.from, email)?;
+        println!("{}", String::from_utf8_lossy(&message.formatted()));
+        Ok(())
+    }
+}
+
+/// Sends nothing, users are verified on signup
+pub struct DisabledMailer;
+
+impl Mailer for DisabledMailer {
+    fn send(&self, email: &Email) -> Result<(), MailerError> {
+        println!("Mailer disabled, not sending \"{}\"", email.subject);
+        Ok(())
+    }
+
+    fn delivers(&self) -> bool {
+        false
+    }
+}
+
+fn var(name: &str) -> Result<String, MailerError> {
+    env::var(name).map_err(|_| MailerError::EnvVarNotSet(name.to_string()))
+}
+
+/// Builds the mailer picked by `MAILER`: `smtp` (the default), `maildir`, `stdout` or
+/// `disabled`. Emails are sent from `MAIL_FROM`, or `SMTP_USER` for SMTP
+pub fn from_env() -> Result<Box<dyn Mailer>, MailerError> {
+    let kind = env::var("MAILER").unwrap_or_else(|_| "smtp".to_string());
+    let from = |default: Option<String>| -> Result<Mailbox, MailerError> {
+        let from = env::var("MAIL_FROM")
+            .ok()
+            .or(default)
+            .unwrap_or_else(|| "place@localhost".to_string());
+        Ok(from.parse()?)
+    };
+
+    match kind.as_str() {
+        "smtp" => {
+            let server = var("SMTP_SERVER")?;
+            let port: u16 = var("SMTP_PORT")?
+                .parse()
+                .map_err(|_| MailerError::ConfigError("SMTP_PORT".to_string()))?;
+            let user = var("SMTP_USER")?;
+            let password = var("SMTP_PASSWORD")?;
+
+            let transport = SmtpTransport::starttls_relay(&server)?
+                .port(port)
+                .credentials(Credentials::new(user.clone(), password))
+                .build();
+
+            Ok(Box::new(SmtpMailer {
+                from: from(Some(user))?,
+                transport,
+            }))
+        }
+        "maildir" => Ok(Box::new(MaildirMailer {
+            from: from(None)?,
+            dir: PathBuf::from(var("MAILDIR")?),
+        })),
+        "stdout" => Ok(Box::new(StdoutMailer { from: from(None)? })),
+        "disabled" => Ok(Box::new(DisabledMailer)),
+        _ => Err(MailerError::ConfigError(format!("unknown MAILER {}", kind))),
+    }
+}
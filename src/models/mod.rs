@@ -1,3 +1,13 @@
 pub mod appstate;
+pub mod canvas;
+pub mod email;
+pub mod event;
+pub mod keys;
+pub mod mailer;
+pub mod region;
+pub mod snapshot;
+pub mod throttle;
+pub mod tiles;
+pub mod timelapse;
 pub mod user;
-mod utils;
+pub mod utils;
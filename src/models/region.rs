@@ -0,0 +1,125 @@
+use std::io::Cursor;
+
+use image::io::{Limits, Reader};
+use image::ImageFormat;
+use serde_derive::Serialize;
+use thiserror::Error;
+
+use crate::database::RegionInfo;
+
+/// Largest side of a protected region, in pixels
+pub const MAX_REGION_SIDE: usize = 8192;
+
+#[derive(Error, Debug)]
+pub enum RegionError {
+    #[error("Invalid mask: {0}")]
+    MaskError(#[from] image::ImageError),
+    #[error("Invalid region: {0}")]
+    InvalidValueError(String),
+}
+
+/// Area of a canvas where placements are rejected. A region with a mask only locks the pixels
+/// of its rectangle where the mask is opaque
+pub struct ProtectedRegion {
+    id: u32,
+    name: String,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    mask: Option<Vec<bool>>,
+}
+
+#[derive(Serialize)]
+pub struct RegionSummary<'a> {
+    pub region_id: u32,
+    pub name: &'a str,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub mask: bool,
+}
+
+/// Decodes a PNG mask into its size and the pixels it locks, in row-major order. Masks larger
+/// than `MAX_REGION_SIDE` are rejected before their pixels are decoded
+pub fn decode_mask(png: &[u8]) -> Result<(usize, usize, Vec<bool>), RegionError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_REGION_SIDE as u32);
+    limits.max_image_height = Some(MAX_REGION_SIDE as u32);
+
+    let mut reader = Reader::with_format(Cursor::new(png), ImageFormat::Png);
+    reader.limits(limits);
+    let image = reader.decode()?.to_rgba8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    check_size(width, height)?;
+
+    let locked = image.pixels().map(|pixel| pixel[3] >= 128).collect();
+    Ok((width, height, locked))
+}
+
+pub fn check_size(width: usize, height: usize) -> Result<(), RegionError> {
+    if width == 0 || height == 0 || width > MAX_REGION_SIDE || height > MAX_REGION_SIDE {
+        return Err(RegionError::InvalidValueError(format!(
+            "size must be between 1 and {}",
+            MAX_REGION_SIDE
+        )));
+    }
+
+    Ok(())
+}
+
+impl ProtectedRegion {
+    /// Builds a region saved in the database. A mask that no longer decodes locks its whole
+    /// rectangle rather than nothing
+    pub fn load(info: RegionInfo) -> Self {
+        let mask = info.mask.and_then(|png| {
+            decode_mask(&png)
+                .map_err(|err| eprintln!("Locking all of region {}: {}", info.name, err))
+                .ok()
+                .filter(|(width, height, _)| (*width, *height) == (info.width, info.height))
+                .map(|(_, _, locked)| locked)
+        });
+
+        Self {
+            id: info.region_id,
+            name: info.name,
+            x: info.x,
+            y: info.y,
+            width: info.width,
+            height: info.height,
+            mask,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        if x < self.x || y < self.y || x - self.x >= self.width || y - self.y >= self.height {
+            return false;
+        }
+
+        match &self.mask {
+            Some(mask) => mask[(y - self.y) * self.width + (x - self.x)],
+            None => true,
+        }
+    }
+
+    pub fn summary(&self) -> RegionSummary<'_> {
+        RegionSummary {
+            region_id: self.id,
+            name: &self.name,
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+            mask: self.mask.is_some(),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"PLSN";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4 + 1 + 8 + 4 + 4;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("Snapshot IO error: {0}")]
+    IoError(#[from] io::Error),
+    #[error("Invalid snapshot: {0}")]
+    InvalidFormatError(String),
+}
+
+/// Pixel arrays of a canvas covering every placement up to pixel id `seq`
+pub struct Snapshot {
+    pub seq: u64,
+    pub width: usize,
+    pub height: usize,
+    pub pixels_color: Vec<u8>,
+    pub pixels_user: Vec<u16>,
+}
+
+impl Snapshot {
+    fn path(dir: &Path, canvas_id: u32) -> PathBuf {
+        dir.join(format!("canvas-{}.snapshot", canvas_id))
+    }
+
+    /// Writes the snapshot of `canvas_id` to `dir`, replacing the previous one atomically
+    pub fn save(&self, dir: &Path, canvas_id: u32) -> Result<(), SnapshotError> {
+        fs::create_dir_all(dir)?;
+
+        let mut pixels = Vec::with_capacity(self.pixels_color.len() * 3);
+        pixels.extend_from_slice(&self.pixels_color);
+        pixels.extend(self.pixels_user.iter().flat_map(|user| user.to_le_bytes()));
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&pixels)?;
+        let body = encoder.finish()?;
+
+        let mut data = Vec::with_capacity(HEADER_SIZE + body.len());
+        data.extend_from_slice(MAGIC);
+        data.push(VERSION);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data.extend_from_slice(&(self.width as u32).to_le_bytes());
+        data.extend_from_slice(&(self.height as u32).to_le_bytes());
+        data.extend_from_slice(&body);
+
+        let path = Self::path(dir, canvas_id);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Reads the snapshot of `canvas_id`, `None` if it was never saved
+    pub fn load(dir: &Path, canvas_id: u32) -> Result<Option<Self>, SnapshotError> {
+        let data = match fs::read(Self::path(dir, canvas_id)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        if data.len() < HEADER_SIZE || &data[0..4] != MAGIC || data[4] != VERSION {
+            return Err(SnapshotError::InvalidFormatError(
+                "unknown header".to_string(),
+            ));
+        }
+
+        let seq = u64::from_le_bytes(data[5..13].try_into().unwrap());
+        let width = u32::from_le_bytes(data[13..17].try_into().unwrap()) as usize;
+        let height = u32::from_le_bytes(data[17..21].try_into().unwrap()) as usize;
+
+        let mut body = Vec::new();
+        ZlibDecoder::new(&data[HEADER_SIZE..]).read_to_end(&mut body)?;
+        if body.len() != width * height * 3 {
+            return Err(SnapshotError::InvalidFormatError(
+                "truncated pixels".to_string(),
+            ));
+        }
+
+        let (pixels_color, pixels_user) = body.split_at(width * height);
+
+        Ok(Some(Self {
+            seq,
+            width,
+            height,
+            pixels_color: pixels_color.to_vec(),
+            pixels_user: pixels_user
+                .chunks_exact(2)
+                .map(|user| u16::from_le_bytes([user[0], user[1]]))
+                .collect(),
+        }))
+    }
+}
@@ -0,0 +1,35 @@
+use std::collections::HashMap;
+
+/// Allows each key at most `limit` requests in any `window` seconds
+pub struct Throttle {
+    limit: usize,
+    window: i64,
+    requests: HashMap<String, Vec<i64>>,
+}
+
+impl Throttle {
+    pub fn new(limit: usize, window: i64) -> Self {
+        Self {
+            limit,
+            window,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Records a request of `key` at `time`, unless it is over its limit
+    pub fn allow(&mut self, key: &str, time: i64) -> bool {
+        let window = self.window;
+        self.requests.retain(|_, times| {
+            times.retain(|&requested_at| time - requested_at < window);
+            !times.is_empty()
+        });
+
+        let times = self.requests.entry(key.to_string()).or_default();
+        if times.len() >= self.limit {
+            return false;
+        }
+
+        times.push(time);
+        true
+    }
+}
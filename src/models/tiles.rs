@@ -0,0 +1,90 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use serde_derive::Serialize;
+
+pub const TILE_SIZE: usize = 256;
+
+pub struct Tile {
+    pub png: Vec<u8>,
+    pub etag: String,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct TileLayout {
+    pub tile_size: usize,
+    pub columns: usize,
+    pub rows: usize,
+}
+
+/// Encoded PNG of every `TILE_SIZE` square of the canvas, re-encoded only when drawn on
+pub struct TileCache {
+    layout: TileLayout,
+    tiles: Vec<Tile>,
+    dirty: Vec<bool>,
+}
+
+impl TileCache {
+    pub fn new(width: usize, height: usize) -> Self {
+        let columns = width.div_ceil(TILE_SIZE);
+        let rows = height.div_ceil(TILE_SIZE);
+
+        Self {
+            layout: TileLayout {
+                tile_size: TILE_SIZE,
+                columns,
+                rows,
+            },
+            tiles: (0..columns * rows)
+                .map(|_| Tile {
+                    png: Vec::new(),
+                    etag: String::new(),
+                })
+                .collect(),
+            dirty: vec![true; columns * rows],
+        }
+    }
+
+    pub fn layout(&self) -> TileLayout {
+        self.layout
+    }
+
+    pub fn mark_dirty(&mut self, x: usize, y: usize) {
+        let index = self.index(x / TILE_SIZE, y / TILE_SIZE);
+        self.dirty[index] = true;
+    }
+
+    /// Tile coordinates of every tile drawn on since the last call
+    pub fn take_dirty_tiles(&mut self) -> Vec<(usize, usize)> {
+        let columns = self.layout.columns;
+        self.dirty
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, dirty)| {
+                std::mem::take(dirty).then_some((index % columns, index / columns))
+            })
+            .collect()
+    }
+
+    pub fn set(&mut self, tx: usize, ty: usize, png: Vec<u8>) {
+        let mut hasher = DefaultHasher::new();
+        png.hash(&mut hasher);
+
+        let index = self.index(tx, ty);
+        self.tiles[index] = Tile {
+            etag: format!("\"{:x}\"", hasher.finish()),
+            png,
+        };
+    }
+
+    pub fn get(&self, tx: usize, ty: usize) -> Option<&Tile> {
+        if tx >= self.layout.columns || ty >= self.layout.rows {
+            return None;
+        }
+        self.tiles.get(self.index(tx, ty))
+    }
+
+    fn index(&self, tx: usize, ty: usize) -> usize {
+        ty * self.layout.columns + tx
+    }
+}
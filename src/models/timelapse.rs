@@ -0,0 +1,202 @@
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+use crate::database::DatabaseUpdate;
+
+const MAX_FRAMES: usize = 2000;
+const MAX_SCALE: u32 = 16;
+/// Pixels of all the frames of a timelapse rendered for a request
+pub const REQUEST_PIXEL_BUDGET: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum TimelapseError {
+    #[error("Invalid option: {0}")]
+    InvalidOptionError(String),
+    #[error("Too many frames, increase the interval")]
+    TooManyFrames,
+    #[error("Timelapse too large, reduce the region, scale or number of frames")]
+    TooLarge,
+    #[error("GIF encoding error: {0}")]
+    EncodingError(#[from] gif::EncodingError),
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TimelapseOptions {
+    /// Seconds of history covered by each frame
+    pub interval: i64,
+    /// Display time of each frame in milliseconds
+    pub delay: u32,
+    pub x: usize,
+    pub y: usize,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub scale: u32,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+impl Default for TimelapseOptions {
+    fn default() -> Self {
+        Self {
+            interval: 60,
+            delay: 100,
+            x: 0,
+            y: 0,
+            width: None,
+            height: None,
+            scale: 1,
+            from: None,
+            to: None,
+        }
+    }
+}
+
+impl TimelapseOptions {
+    /// Parses `--interval`, `--delay`, `--region x,y,w,h`, `--scale`, `--from` and `--to`
+    pub fn from_args(args: &[String]) -> Result<Self, TimelapseError> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| {
+                TimelapseError::InvalidOptionError(format!("{} needs a value", flag))
+            })?;
+
+            match flag.as_str() {
+                "--interval" => options.interval = parse_arg(flag, value)?,
+                "--delay" => options.delay = parse_arg(flag, value)?,
+                "--scale" => options.scale = parse_arg(flag, value)?,
+                "--from" => options.from = Some(parse_arg(flag, value)?),
+                "--to" => options.to = Some(parse_arg(flag, value)?),
+                "--region" => {
+                    let region = value
+                        .split(',')
+                        .map(|part| parse_arg(flag, part))
+                        .collect::<Result<Vec<usize>, _>>()?;
+                    if region.len() != 4 {
+                        return Err(TimelapseError::InvalidOptionError(
+                            "--region must be x,y,width,height".to_string(),
+                        ));
+                    }
+                    options.x = region[0];
+                    options.y = region[1];
+                    options.width = Some(region[2]);
+                    options.height = Some(region[3]);
+                }
+                _ => {
+                    return Err(TimelapseError::InvalidOptionError(format!(
+                        "unknown flag {}",
+                        flag
+                    )))
+                }
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, TimelapseError> {
+    value
+        .parse()
+        .map_err(|_| TimelapseError::InvalidOptionError(format!("invalid value for {}", flag)))
+}
+
+/// Replays `updates` (sorted by timestamp) onto a blank canvas and encodes one GIF frame
+/// per `options.interval` seconds of history. With a `pixel_budget`, timelapses whose frames
+/// have more pixels in total are refused
+pub fn render_timelapse(
+    updates: &[DatabaseUpdate],
+    width: usize,
+    height: usize,
+    palette: &[(u8, u8, u8)],
+    blank: u8,
+    options: &TimelapseOptions,
+    pixel_budget: Option<usize>,
+) -> Result<Vec<u8>, TimelapseError> {
+    let region_width = options.width.unwrap_or(width.saturating_sub(options.x));
+    let region_height = options.height.unwrap_or(height.saturating_sub(options.y));
+
+    if options.interval <= 0 {
+        return Err(TimelapseError::InvalidOptionError(
+            "interval must be positive".to_string(),
+        ));
+    }
+    if options.scale == 0 || options.scale > MAX_SCALE {
+        return Err(TimelapseError::InvalidOptionError(format!(
+            "scale must be between 1 and {}",
+            MAX_SCALE
+        )));
+    }
+    let in_bounds = |start: usize, size: usize, limit: usize| {
+        start.checked_add(size).is_some_and(|end| end <= limit)
+    };
+    if region_width == 0
+        || region_height == 0
+        || !in_bounds(options.x, region_width, width)
+        || !in_bounds(options.y, region_height, height)
+    {
+        return Err(TimelapseError::InvalidOptionError(
+            "region out of bounds".to_string(),
+        ));
+    }
+
+    let scale = options.scale as usize;
+    let frame_width = u16::try_from(region_width * scale)
+        .map_err(|_| TimelapseError::InvalidOptionError("region too large".to_string()))?;
+    let frame_height = u16::try_from(region_height * scale)
+        .map_err(|_| TimelapseError::InvalidOptionError("region too large".to_string()))?;
+
+    let (start, end) = match (updates.first(), updates.last()) {
+        (Some(first), Some(last)) => (
+            options.from.unwrap_or(first.timestamp),
+            options.to.unwrap_or(last.timestamp),
+        ),
+        _ => (options.from.unwrap_or(0), options.to.unwrap_or(0)),
+    };
+    let frame_count = (end.saturating_sub(start).max(0) / options.interval) as usize + 1;
+    if frame_count > MAX_FRAMES {
+        return Err(TimelapseError::TooManyFrames);
+    }
+    let pixels = frame_width as usize * frame_height as usize * frame_count;
+    if pixel_budget.is_some_and(|budget| pixels > budget) {
+        return Err(TimelapseError::TooLarge);
+    }
+
+    let global_palette: Vec<u8> = palette.iter().flat_map(|&(r, g, b)| [r, g, b]).collect();
+
+    let mut gif = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif, frame_width, frame_height, &global_palette)?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+
+        let mut pixels_color = vec![blank; width * height];
+        let mut updates = updates.iter().peekable();
+
+        for frame_index in 0..frame_count {
+            let frame_end = start + (frame_index as i64 + 1) * options.interval;
+            while let Some(update) = updates.next_if(|update| update.timestamp < frame_end) {
+                if update.x < width && update.y < height {
+                    pixels_color[update.x * height + update.y] = update.color;
+                }
+            }
+
+            let mut buffer = Vec::with_capacity(frame_width as usize * frame_height as usize);
+            for row in 0..frame_height as usize {
+                let y = options.y + row / scale;
+                for column in 0..frame_width as usize {
+                    let x = options.x + column / scale;
+                    buffer.push(pixels_color[x * height + y]);
+                }
+            }
+
+            let mut frame =
+                gif::Frame::from_indexed_pixels(frame_width, frame_height, buffer, None);
+            frame.delay = (options.delay / 10).min(u16::MAX as u32) as u16;
+            encoder.write_frame(&frame)?;
+        }
+    }
+
+    Ok(gif)
+}
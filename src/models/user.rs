@@ -1,22 +1,65 @@
-use serde_derive::Serialize;
+use std::str::FromStr;
+
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
+        match role {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role {}", role)),
+        }
+    }
+}
 
 #[derive(Serialize, Clone)]
 pub struct User {
     pub username: String,
-    pub cooldown: i64,
     pub score: u32,
     pub rank: u32,
     pub verified: bool,
+    /// Only shown to the user and admins, not on the leaderboard
+    #[serde(skip)]
+    pub role: Role,
+    #[serde(skip)]
+    pub banned: bool,
+    /// Tokens issued with another version are revoked
+    #[serde(skip)]
+    pub token_version: u32,
 }
 
 impl User {
     pub fn new(username: String, score: u32, verified: bool) -> Self {
         Self {
             username,
-            cooldown: 0,
             score,
             rank: 0,
             verified,
+            role: Role::User,
+            banned: false,
+            token_version: 0,
         }
     }
 }
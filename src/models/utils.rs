@@ -1,15 +1,41 @@
 use serde_derive::{Deserialize, Serialize};
 
+use crate::models::appstate::AppStateError;
+
+/// Palette indices are sent as a `u8`
+pub const MAX_PALETTE_SIZE: usize = 256;
+
 #[derive(Serialize, Deserialize)]
 pub struct ColorFile {
     pub colors: Vec<String>,
 }
 
-pub fn hex_to_rgb(hex: &str) -> (u8, u8, u8) {
-    let hex = hex.trim_start_matches('#');
-    let r = u8::from_str_radix(&hex[0..2], 16).unwrap();
-    let g = u8::from_str_radix(&hex[2..4], 16).unwrap();
-    let b = u8::from_str_radix(&hex[4..6], 16).unwrap();
+/// Parses a `#rrggbb` color, the `#` being optional
+pub fn hex_to_rgb(hex: &str) -> Option<(u8, u8, u8)> {
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |start: usize| u8::from_str_radix(&hex[start..start + 2], 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Converts a palette to RGB, checking that it has between 1 and 256 valid colors
+pub fn parse_palette(colors: &[String]) -> Result<Vec<(u8, u8, u8)>, AppStateError> {
+    if colors.is_empty() || colors.len() > MAX_PALETTE_SIZE {
+        return Err(AppStateError::InvalidValueError(format!(
+            "palette must have between 1 and {} colors",
+            MAX_PALETTE_SIZE
+        )));
+    }
 
-    (r, g, b)
+    colors
+        .iter()
+        .map(|color| {
+            hex_to_rgb(color).ok_or_else(|| {
+                AppStateError::InvalidValueError(format!("{} is not a #rrggbb color", color))
+            })
+        })
+        .collect()
 }
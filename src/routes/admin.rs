@@ -0,0 +1,914 @@
+use std::sync::RwLock;
+
+use actix::Addr;
+use actix_web::{delete, error, get, post, routes, web, Error, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::database::{Database, DatabaseError, ModerationEntry, RegionInfo};
+use crate::flusher::{without_flush, Flush, Flusher};
+use crate::models::appstate::{AppState, AppStateError};
+use crate::models::event::EventSchedule;
+use crate::models::region::{self, ProtectedRegion};
+use crate::models::user::{Role, User};
+use crate::models::utils::parse_palette;
+use crate::routes::utils::{find_canvas_mut, require_role, CanvasName};
+
+const MAX_CANVAS_SIDE: usize = 8192;
+const MAX_LOG_PAGE_SIZE: u64 = 200;
+
+#[derive(Deserialize)]
+struct ResizeInfo {
+    width: usize,
+    height: usize,
+}
+
+#[derive(Deserialize)]
+struct RoleInfo {
+    role: Role,
+}
+
+#[derive(Serialize)]
+struct ModeratedUser<'a> {
+    user_id: u16,
+    #[serde(flatten)]
+    user: &'a User,
+    role: Role,
+    banned: bool,
+}
+
+#[derive(Deserialize)]
+struct UserPath {
+    user_id: u16,
+}
+
+#[derive(Deserialize)]
+struct RollbackInfo {
+    from: i64,
+    to: Option<i64>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct RollbackResult {
+    pixels: usize,
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    page: Option<u64>,
+    limit: Option<u64>,
+    actor: Option<u16>,
+    user: Option<u16>,
+}
+
+#[derive(Serialize)]
+struct LogPage {
+    page: u64,
+    limit: u64,
+    total: u64,
+    entries: Vec<ModerationEntry>,
+}
+
+#[derive(Deserialize)]
+struct RegionCreation {
+    name: String,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+#[derive(Deserialize)]
+struct MaskQuery {
+    name: String,
+    x: usize,
+    y: usize,
+}
+
+#[derive(Deserialize)]
+struct ScheduleInfo {
+    opens_at: Option<i64>,
+    closes_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ColorInfo {
+    color: String,
+}
+
+#[derive(Deserialize)]
+struct ColorPath {
+    color: u8,
+}
+
+#[derive(Deserialize)]
+struct CanvasCreation {
+    name: String,
+    width: usize,
+    height: usize,
+    cooldown: Option<u16>,
+    palette: Option<Vec<String>>,
+}
+
+fn check_size(width: usize, height: usize) -> Result<(), Error> {
+    if width == 0 || height == 0 {
+        return Err(error::ErrorBadRequest("size must not be zero"));
+    }
+    if width > MAX_CANVAS_SIDE || height > MAX_CANVAS_SIDE {
+        return Err(error::ErrorBadRequest("size too large"));
+    }
+    Ok(())
+}
+
+#[routes]
+#[post("/api/admin/resize")]
+#[post("/api/c/{canvas}/admin/resize")]
+async fn resize_canvas(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    flusher: web::Data<Addr<Flusher>>,
+    canvas: CanvasName,
+    info: web::Json<ResizeInfo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(&req, &appstate, Role::Admin)?;
+    check_size(info.width, info.height)?;
+
+    let canvas = find_canvas_mut(&mut appstate, &canvas)?;
+    let previous = canvas.get_size();
+
+    canvas
+        .resize(info.width, info.height, &database)
+        .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    let size = canvas.get_size();
+
+    flusher.do_send(Flush);
+
+    log_action(
+        &database,
+        actor_id,
+        "resize",
+        None,
+        Some(canvas.name()),
+        json!({ "width": size.0, "height": size.1, "previous": previous }),
+    );
+
+    Ok(HttpResponse::Ok().json(size))
+}
+
+#[post("/api/admin/canvases")]
+async fn create_canvas(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    info: web::Json<CanvasCreation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(&req, &appstate, Role::Admin)?;
+    check_size(info.width, info.height)?;
+
+    if info.name.is_empty()
+        || info.name.len() > 32
+        || !info
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(error::ErrorBadRequest(
+            "name must be 1 to 32 letters, digits, '-' or '_'",
+        ));
+    }
+
+    if let Some(palette) = &info.palette {
+        parse_palette(palette).map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    }
+
+    let info = info.into_inner();
+    let canvas = appstate
+        .create_canvas(
+            &info.name,
+            info.width,
+            info.height,
+            info.cooldown,
+            info.palette,
+            &database,
+        )
+        .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    let summary = canvas.summary();
+
+    log_action(
+        &database,
+        actor_id,
+        "create_canvas",
+        None,
+        Some(&summary.name),
+        json!({
+            "width": summary.width,
+            "height": summary.height,
+            "cooldown": summary.cooldown,
+            "palette": summary.palette,
+        }),
+    );
+
+    Ok(HttpResponse::Created().json(summary))
+}
+
+/// Records a moderation action. The action already happened, so a failure is only reported
+fn log_action(
+    database: &Database,
+    actor: u16,
+    action: &str,
+    target_user: Option<u16>,
+    canvas: Option<&str>,
+    params: serde_json::Value,
+) {
+    if let Err(err) = database.log_moderation(actor, action, target_user, canvas, &params) {
+        eprintln!("Error writing moderation log: {}", err);
+    }
+}
+
+/// Checks that `actor_id` may moderate `target_id`: only admins can act on moderators
+/// and admins, and nobody can act on themselves
+fn check_target(appstate: &AppState, actor_id: u16, target_id: u16) -> Result<(), Error> {
+    if actor_id == target_id {
+        return Err(error::ErrorBadRequest("cannot moderate yourself"));
+    }
+
+    let actor = appstate
+        .get_user(actor_id)
+        .ok_or_else(|| error::ErrorUnauthorized("invalid user"))?;
+    let target = appstate
+        .get_user(target_id)
+        .ok_or_else(|| error::ErrorNotFound("no such user"))?;
+
+    if target.role >= actor.role && actor.role != Role::Admin {
+        return Err(error::ErrorForbidden("cannot moderate this user"));
+    }
+
+    Ok(())
+}
+
+fn moderated_user(appstate: &AppState, user_id: u16) -> Result<HttpResponse, Error> {
+    let user = appstate
+        .get_user(user_id)
+        .ok_or_else(|| error::ErrorNotFound("no such user"))?;
+
+    Ok(HttpResponse::Ok().json(ModeratedUser {
+        user_id,
+        user,
+        role: user.role,
+        banned: user.banned,
+    }))
+}
+
+fn set_banned(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    req: &HttpRequest,
+    user_id: u16,
+    banned: bool,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(req, &appstate, Role::Moderator)?;
+    check_target(&appstate, actor_id, user_id)?;
+
+    database
+        .set_banned(user_id, banned)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    if let Some(user) = appstate.get_user_mut(user_id) {
+        user.banned = banned;
+    }
+    if banned {
+        appstate
+            .revoke_tokens(user_id, database)
+            .map_err(|_| error::ErrorInternalServerError("database error"))?;
+    }
+
+    let action = if banned { "ban" } else { "unban" };
+    log_action(database, actor_id, action, Some(user_id), None, json!({}));
+
+    moderated_user(&appstate, user_id)
+}
+
+fn set_verified(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    req: &HttpRequest,
+    user_id: u16,
+    verified: bool,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(req, &appstate, Role::Admin)?;
+    appstate
+        .get_user(user_id)
+        .ok_or_else(|| error::ErrorNotFound("no such user"))?;
+
+    database
+        .set_verified(user_id, verified)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    if let Some(user) = appstate.get_user_mut(user_id) {
+        user.verified = verified;
+    }
+
+    let action = if verified { "verify" } else { "unverify" };
+    log_action(database, actor_id, action, Some(user_id), None, json!({}));
+
+    moderated_user(&appstate, user_id)
+}
+
+#[post("/api/admin/users/{user_id}/ban")]
+async fn ban_user(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u16>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_banned(&appstate, &database, &req, path.into_inner(), true)
+}
+
+#[post("/api/admin/users/{user_id}/unban")]
+async fn unban_user(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u16>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_banned(&appstate, &database, &req, path.into_inner(), false)
+}
+
+#[post("/api/admin/users/{user_id}/verify")]
+async fn verify_user(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u16>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_verified(&appstate, &database, &req, path.into_inner(), true)
+}
+
+#[post("/api/admin/users/{user_id}/unverify")]
+async fn unverify_user(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u16>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_verified(&appstate, &database, &req, path.into_inner(), false)
+}
+
+#[post("/api/admin/users/{user_id}/role")]
+async fn set_user_role(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u16>,
+    info: web::Json<RoleInfo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner();
+
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(&req, &appstate, Role::Admin)?;
+    check_target(&appstate, actor_id, user_id)?;
+
+    database
+        .set_role(user_id, info.role)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    let mut previous = None;
+    if let Some(user) = appstate.get_user_mut(user_id) {
+        previous = Some(std::mem::replace(&mut user.role, info.role));
+    }
+
+    log_action(
+        &database,
+        actor_id,
+        "role",
+        Some(user_id),
+        None,
+        json!({ "role": info.role, "previous": previous }),
+    );
+
+    moderated_user(&appstate, user_id)
+}
+
+/// Puts back what the placements made by `user_id` between `from` and `to` covered, on every
+/// pixel where one of them is still the latest. The database is only used without the appstate
+/// lock. Returns the number of pixels changed, or that would change with `dry_run`. Must run
+/// while no flush is saving placements
+fn rollback(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    canvas: &str,
+    user_id: u16,
+    actor_id: u16,
+    (from, to): (i64, i64),
+    dry_run: bool,
+) -> Result<usize, AppStateError> {
+    let (canvas_id, pending) = appstate
+        .read()
+        .map_err(|_| AppStateError::LockError)?
+        .pending_updates(canvas)?;
+
+    // Saving them again on the next flush is a no-op
+    database
+        .save_pixel_updates(&pending)
+        .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+    let pixels = database
+        .get_rollback_pixels(canvas_id, user_id, from, to)
+        .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+    if dry_run {
+        return Ok(pixels.len());
+    }
+
+    let rolled_back = database
+        .mark_rolled_back(canvas_id, user_id, from, to)
+        .map_err(|e| AppStateError::DatabaseError(e.to_string()))?;
+
+    appstate
+        .write()
+        .map_err(|_| AppStateError::LockError)?
+        .restore_pixels(canvas, user_id, actor_id, &pixels, rolled_back)
+}
+
+#[routes]
+#[post("/api/admin/users/{user_id}/rollback")]
+#[post("/api/c/{canvas}/admin/users/{user_id}/rollback")]
+async fn rollback_user(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    flusher: web::Data<Addr<Flusher>>,
+    canvas: CanvasName,
+    path: web::Path<UserPath>,
+    info: web::Json<RollbackInfo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.user_id;
+    let window = (info.from, info.to.unwrap_or(i64::MAX));
+    if window.0 > window.1 {
+        return Err(error::ErrorBadRequest("from must not be after to"));
+    }
+
+    let actor_id = {
+        let appstate = appstate
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+        let actor_id = require_role(&req, &appstate, Role::Moderator)?;
+        check_target(&appstate, actor_id, user_id)?;
+        actor_id
+    };
+
+    let dry_run = info.dry_run;
+    let params = json!({ "from": info.from, "to": info.to });
+    let pixels = web::block(move || {
+        let pixels = without_flush(|| {
+            rollback(
+                &appstate, &database, &canvas.0, user_id, actor_id, window, dry_run,
+            )
+        })??;
+
+        if !dry_run {
+            let mut params = params;
+            params["pixels"] = pixels.into();
+            log_action(
+                &database,
+                actor_id,
+                "rollback",
+                Some(user_id),
+                Some(&canvas.0),
+                params,
+            );
+        }
+
+        Ok::<_, AppStateError>(pixels)
+    })
+    .await
+    .map_err(|_| error::ErrorInternalServerError("rollback error"))?
+    .map_err(|err| match err {
+        AppStateError::NoSuchCanvasError => error::ErrorNotFound("no such canvas"),
+        _ => error::ErrorInternalServerError(err.to_string()),
+    })?;
+
+    if !dry_run && pixels > 0 {
+        flusher.do_send(Flush);
+    }
+
+    Ok(HttpResponse::Ok().json(RollbackResult { pixels, dry_run }))
+}
+
+#[get("/api/admin/log")]
+async fn get_moderation_log(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    query: web::Query<LogQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    {
+        let appstate = appstate
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+        require_role(&req, &appstate, Role::Moderator)?;
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_LOG_PAGE_SIZE);
+
+    let (entries, total) = database
+        .get_moderation_log(
+            query.actor,
+            query.user,
+            (page - 1).saturating_mul(limit),
+            limit,
+        )
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    Ok(HttpResponse::Ok().json(LogPage {
+        page,
+        limit,
+        total,
+        entries,
+    }))
+}
+
+/// Area of a new region, a rectangle or the PNG mask it takes the size of
+enum RegionArea {
+    Rect(usize, usize),
+    Mask(Vec<u8>),
+}
+
+fn protect_region(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    req: &HttpRequest,
+    canvas: &CanvasName,
+    name: String,
+    (x, y): (usize, usize),
+    area: RegionArea,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(req, &appstate, Role::Admin)?;
+
+    if name.trim().is_empty() || name.len() > 64 {
+        return Err(error::ErrorBadRequest("name must be 1 to 64 characters"));
+    }
+    let (width, height, mask) = match area {
+        RegionArea::Rect(width, height) => (width, height, None),
+        RegionArea::Mask(png) => {
+            let (width, height, _) =
+                region::decode_mask(&png).map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+            (width, height, Some(png))
+        }
+    };
+    region::check_size(width, height).map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+    let fits = |start: usize, size: usize| {
+        start
+            .checked_add(size)
+            .is_some_and(|end| end <= MAX_CANVAS_SIDE)
+    };
+    if !fits(x, width) || !fits(y, height) {
+        return Err(error::ErrorBadRequest(format!(
+            "region must fit in {}x{}",
+            MAX_CANVAS_SIDE, MAX_CANVAS_SIDE
+        )));
+    }
+
+    let canvas = find_canvas_mut(&mut appstate, canvas)?;
+    let region_id = database
+        .create_region(canvas.id(), &name, (x, y, width, height), mask.as_deref())
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    let region = ProtectedRegion::load(RegionInfo {
+        region_id,
+        name,
+        x,
+        y,
+        width,
+        height,
+        mask,
+    });
+    let summary = json!(region.summary());
+    canvas.add_region(region);
+
+    log_action(
+        database,
+        actor_id,
+        "protect",
+        None,
+        Some(canvas.name()),
+        summary.clone(),
+    );
+
+    Ok(HttpResponse::Created().json(summary))
+}
+
+#[routes]
+#[post("/api/admin/regions")]
+#[post("/api/c/{canvas}/admin/regions")]
+async fn create_region(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    info: web::Json<RegionCreation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let info = info.into_inner();
+
+    protect_region(
+        &appstate,
+        &database,
+        &req,
+        &canvas,
+        info.name,
+        (info.x, info.y),
+        RegionArea::Rect(info.width, info.height),
+    )
+}
+
+/// Takes the PNG mask as the request body, locking the pixels where it is opaque
+#[routes]
+#[post("/api/admin/regions/mask")]
+#[post("/api/c/{canvas}/admin/regions/mask")]
+async fn create_mask_region(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    query: web::Query<MaskQuery>,
+    body: web::Bytes,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+
+    protect_region(
+        &appstate,
+        &database,
+        &req,
+        &canvas,
+        query.name,
+        (query.x, query.y),
+        RegionArea::Mask(body.to_vec()),
+    )
+}
+
+#[delete("/api/admin/regions/{region_id}")]
+async fn delete_region(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    path: web::Path<u32>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let region_id = path.into_inner();
+
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(&req, &appstate, Role::Admin)?;
+
+    database.delete_region(region_id).map_err(|err| match err {
+        DatabaseError::NoSuchRow => error::ErrorNotFound("no such region"),
+        _ => error::ErrorInternalServerError("database error"),
+    })?;
+    let canvas = appstate.remove_region(region_id);
+
+    log_action(
+        &database,
+        actor_id,
+        "unprotect",
+        None,
+        canvas.as_deref(),
+        json!({ "region_id": region_id }),
+    );
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn update_schedule(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    flusher: &Addr<Flusher>,
+    req: &HttpRequest,
+    canvas: &CanvasName,
+    action: &str,
+    update: impl FnOnce(&mut EventSchedule),
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(req, &appstate, Role::Admin)?;
+    let canvas = find_canvas_mut(&mut appstate, canvas)?;
+
+    let mut schedule = canvas.schedule();
+    update(&mut schedule);
+
+    let closed = canvas
+        .set_schedule(schedule, database)
+        .map_err(|err| error::ErrorInternalServerError(err.to_string()))?;
+    if closed {
+        flusher.do_send(Flush);
+    }
+
+    log_action(
+        database,
+        actor_id,
+        action,
+        None,
+        Some(canvas.name()),
+        json!(schedule),
+    );
+
+    Ok(HttpResponse::Ok().json(canvas.event_status(Utc::now().timestamp())))
+}
+
+#[routes]
+#[post("/api/admin/schedule")]
+#[post("/api/c/{canvas}/admin/schedule")]
+async fn set_schedule(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    flusher: web::Data<Addr<Flusher>>,
+    canvas: CanvasName,
+    info: web::Json<ScheduleInfo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    if let (Some(opens_at), Some(closes_at)) = (info.opens_at, info.closes_at) {
+        if opens_at >= closes_at {
+            return Err(error::ErrorBadRequest("opens_at must be before closes_at"));
+        }
+    }
+
+    update_schedule(
+        &appstate,
+        &database,
+        &flusher,
+        &req,
+        &canvas,
+        "schedule",
+        |schedule| {
+            schedule.opens_at = info.opens_at;
+            schedule.closes_at = info.closes_at;
+        },
+    )
+}
+
+#[routes]
+#[post("/api/admin/freeze")]
+#[post("/api/c/{canvas}/admin/freeze")]
+async fn freeze_canvas(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    flusher: web::Data<Addr<Flusher>>,
+    canvas: CanvasName,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    update_schedule(
+        &appstate,
+        &database,
+        &flusher,
+        &req,
+        &canvas,
+        "freeze",
+        |schedule| schedule.frozen = true,
+    )
+}
+
+#[routes]
+#[post("/api/admin/unfreeze")]
+#[post("/api/c/{canvas}/admin/unfreeze")]
+async fn unfreeze_canvas(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    flusher: web::Data<Addr<Flusher>>,
+    canvas: CanvasName,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    update_schedule(
+        &appstate,
+        &database,
+        &flusher,
+        &req,
+        &canvas,
+        "unfreeze",
+        |schedule| schedule.frozen = false,
+    )
+}
+
+#[routes]
+#[post("/api/admin/palette")]
+#[post("/api/c/{canvas}/admin/palette")]
+async fn add_color(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    info: web::Json<ColorInfo>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(&req, &appstate, Role::Admin)?;
+    let canvas = find_canvas_mut(&mut appstate, &canvas)?;
+
+    let index = canvas
+        .add_color(&info.color, &database)
+        .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+
+    log_action(
+        &database,
+        actor_id,
+        "add_color",
+        None,
+        Some(canvas.name()),
+        json!({ "color": info.color, "index": index }),
+    );
+
+    Ok(HttpResponse::Created().json(canvas.get_palette_info()))
+}
+
+fn set_color_retired(
+    appstate: &RwLock<AppState>,
+    database: &Database,
+    req: &HttpRequest,
+    canvas: &CanvasName,
+    color: u8,
+    retired: bool,
+) -> Result<HttpResponse, Error> {
+    let mut appstate = appstate
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+    let actor_id = require_role(req, &appstate, Role::Admin)?;
+    let canvas = find_canvas_mut(&mut appstate, canvas)?;
+
+    canvas
+        .set_retired(color, retired, database)
+        .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+
+    let action = if retired {
+        "retire_color"
+    } else {
+        "unretire_color"
+    };
+    log_action(
+        database,
+        actor_id,
+        action,
+        None,
+        Some(canvas.name()),
+        json!({ "index": color }),
+    );
+
+    Ok(HttpResponse::Ok().json(canvas.get_palette_info()))
+}
+
+#[routes]
+#[post("/api/admin/palette/{color}/retire")]
+#[post("/api/c/{canvas}/admin/palette/{color}/retire")]
+async fn retire_color(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    path: web::Path<ColorPath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_color_retired(&appstate, &database, &req, &canvas, path.color, true)
+}
+
+#[routes]
+#[post("/api/admin/palette/{color}/unretire")]
+#[post("/api/c/{canvas}/admin/palette/{color}/unretire")]
+async fn unretire_color(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    path: web::Path<ColorPath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    set_color_retired(&appstate, &database, &req, &canvas, path.color, false)
+}
@@ -1,3 +1,4 @@
+pub mod admin;
 pub mod place;
 pub mod user;
-mod utils;
+pub mod utils;
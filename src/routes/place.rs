@@ -1,52 +1,258 @@
 use std::sync::RwLock;
 
-use actix_web::{error, get, post, web, Error, HttpRequest, HttpResponse};
+use actix_web::{error, get, http::header, routes, web, Error, HttpRequest, HttpResponse};
 use chrono::Utc;
 use serde_derive::Deserialize;
 
-use crate::database::Database;
+use crate::database::{Database, DatabaseError};
 use crate::models::appstate::AppState;
-use crate::routes::utils::token_to_id;
+use crate::models::canvas::encode_canvas;
+use crate::models::event::EventState;
+use crate::models::timelapse::{
+    render_timelapse, TimelapseError, TimelapseOptions, REQUEST_PIXEL_BUDGET,
+};
+use crate::models::user::Role;
+use crate::routes::utils::{find_canvas, token_to_id, CanvasName};
 
 #[derive(Deserialize)]
-struct DrawInfo {
+pub struct DrawInfo {
+    pub x: u32,
+    pub y: u32,
+    pub color: u8,
+}
+
+#[derive(Deserialize)]
+struct UpdatesQuery {
+    since: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TilePath {
+    tx: usize,
+    ty: usize,
+}
+
+#[derive(Deserialize)]
+struct TimestampPath {
+    timestamp: i64,
+}
+
+#[derive(Deserialize)]
+struct PixelPath {
     x: u32,
     y: u32,
-    color: u8,
 }
 
+#[get("/api/canvases")]
+async fn get_canvases(appstate: web::Data<RwLock<AppState>>) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    Ok(HttpResponse::Ok().json(appstate.get_canvases()))
+}
+
+#[routes]
 #[get("/api/png")]
+#[get("/api/c/{canvas}/png")]
 async fn get_png(
     appstate: web::Data<RwLock<AppState>>,
-    database: web::Data<Database>,
+    canvas: CanvasName,
 ) -> Result<HttpResponse, Error> {
-    let mut appstate = appstate
-        .write()
-        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    appstate
-        .try_update(&database)
-        .map_err(|err| eprintln!("appstate error: {}", err))
-        .ok();
+    let canvas = find_canvas(&appstate, &canvas)?;
+
+    if canvas.get_png().is_empty() {
+        return Err(error::ErrorServiceUnavailable("canvas not rendered yet"));
+    }
 
     Ok(HttpResponse::Ok()
         .content_type("image/png")
-        .body(appstate.get_png().clone()))
+        .insert_header(("X-Sequence", canvas.get_png_seq()))
+        .body(canvas.get_png().clone()))
 }
 
+#[routes]
+#[get("/api/tiles")]
+#[get("/api/c/{canvas}/tiles")]
+async fn get_tile_layout(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    Ok(HttpResponse::Ok().json(find_canvas(&appstate, &canvas)?.get_tile_layout()))
+}
+
+#[routes]
+#[get("/api/tile/{tx}/{ty}.png")]
+#[get("/api/c/{canvas}/tile/{tx}/{ty}.png")]
+async fn get_tile(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+    path: web::Path<TilePath>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    let canvas = find_canvas(&appstate, &canvas)?;
+
+    let tile = canvas
+        .get_tile(path.tx, path.ty)
+        .ok_or_else(|| error::ErrorNotFound("no such tile"))?;
+
+    if tile.png.is_empty() {
+        return Err(error::ErrorServiceUnavailable("tile not rendered yet"));
+    }
+
+    let not_modified = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value == tile.etag);
+
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::ETAG, tile.etag.clone()))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Sequence", canvas.get_flush_seq()));
+
+    if not_modified {
+        return Ok(response.finish());
+    }
+
+    Ok(response.content_type("image/png").body(tile.png.clone()))
+}
+
+#[routes]
+#[get("/api/png/at/{timestamp}")]
+#[get("/api/c/{canvas}/png/at/{timestamp}")]
+async fn get_png_at(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    path: web::Path<TimestampPath>,
+) -> Result<HttpResponse, Error> {
+    let (canvas_id, width, height, palette, blank) = {
+        let appstate = appstate
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+        let canvas = find_canvas(&appstate, &canvas)?;
+        let (width, height) = canvas.get_size();
+        (
+            canvas.id(),
+            width,
+            height,
+            canvas.get_palette().clone(),
+            canvas.blank_color(),
+        )
+    };
+
+    let timestamp = path.timestamp;
+    let png = web::block(move || {
+        database
+            .get_pixels_at(canvas_id, width, height, blank, timestamp)
+            .map(|(pixels_color, _)| encode_canvas(&palette, &pixels_color, width, height))
+    })
+    .await
+    .map_err(|_| error::ErrorInternalServerError("blocking error"))?
+    .map_err(|_| error::ErrorInternalServerError("database error"))?
+    .map_err(|err| error::ErrorInternalServerError(format!("appstate error: {}", err)))?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(png))
+}
+
+#[routes]
+#[get("/api/timelapse")]
+#[get("/api/c/{canvas}/timelapse")]
+async fn get_timelapse(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<Database>,
+    canvas: CanvasName,
+    options: web::Query<TimelapseOptions>,
+) -> Result<HttpResponse, Error> {
+    let (canvas_id, width, height, palette, blank) = {
+        let appstate = appstate
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+        let canvas = find_canvas(&appstate, &canvas)?;
+        let (width, height) = canvas.get_size();
+        (
+            canvas.id(),
+            width,
+            height,
+            canvas.get_palette().clone(),
+            canvas.blank_color(),
+        )
+    };
+
+    let gif = web::block(move || {
+        let options = options.into_inner();
+        database
+            .get_pixel_history(canvas_id, options.to.unwrap_or(i64::MAX))
+            .map(|updates| {
+                render_timelapse(
+                    &updates,
+                    width,
+                    height,
+                    &palette,
+                    blank,
+                    &options,
+                    Some(REQUEST_PIXEL_BUDGET),
+                )
+            })
+    })
+    .await
+    .map_err(|_| error::ErrorInternalServerError("timelapse error"))?
+    .map_err(|_| error::ErrorInternalServerError("database error"))?
+    .map_err(|err| match err {
+        TimelapseError::EncodingError(_) => error::ErrorInternalServerError("gif encoding error"),
+        _ => error::ErrorBadRequest(err.to_string()),
+    })?;
+
+    Ok(HttpResponse::Ok().content_type("image/gif").body(gif))
+}
+
+#[routes]
 #[get("/api/updates")]
-async fn get_updates(appstate: web::Data<RwLock<AppState>>) -> Result<HttpResponse, Error> {
+#[get("/api/c/{canvas}/updates")]
+async fn get_updates(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+    query: web::Query<UpdatesQuery>,
+) -> Result<HttpResponse, Error> {
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    Ok(HttpResponse::Ok().json(appstate.get_message_updates()))
+    let canvas = find_canvas(&appstate, &canvas)?;
+
+    let updates = canvas
+        .get_message_updates(query.since)
+        .map_err(|err| error::ErrorGone(err.to_string()))?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Sequence", canvas.get_seq()))
+        .json(updates))
 }
 
+#[routes]
 #[post("/api/draw")]
+#[post("/api/c/{canvas}/draw")]
 async fn draw(
     appstate: web::Data<RwLock<AppState>>,
-    database: web::Data<Database>,
+    canvas: CanvasName,
     info: web::Json<DrawInfo>,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
@@ -54,23 +260,65 @@ async fn draw(
         .write()
         .map_err(|err| error::ErrorInternalServerError(format!("appstate error: {}", err)))?;
 
-    let user_id = token_to_id(req, appstate.jwt_secret().as_bytes())
-        .map_err(|_| error::ErrorBadRequest("Failed to decode token"))?;
+    let user_id = token_to_id(req, &appstate)?;
+
+    let cooldown = place_pixel(&mut appstate, &canvas, user_id, &info)?;
+
+    Ok(HttpResponse::Ok().json(cooldown))
+}
+
+/// Validates and applies a placement, returning the cooldown before the next one
+pub fn place_pixel(
+    appstate: &mut AppState,
+    canvas: &CanvasName,
+    user_id: u16,
+    info: &DrawInfo,
+) -> Result<u16, Error> {
+    if appstate.is_closing() {
+        return Err(error::ErrorServiceUnavailable("server is shutting down"));
+    }
 
     let user = appstate
         .get_user(user_id)
         .ok_or(error::ErrorBadRequest("invalid user"))?;
 
+    let canvas_state = find_canvas(appstate, canvas)?;
+
     let time = Utc::now().timestamp();
 
-    if info.x >= appstate.get_size().0 as u32 || info.y >= appstate.get_size().1 as u32 {
+    match canvas_state.event_state(time) {
+        EventState::Pending => return Err(error::ErrorForbidden("event has not started")),
+        EventState::Frozen => return Err(error::ErrorForbidden("canvas is frozen")),
+        EventState::Closed => return Err(error::ErrorForbidden("event is over")),
+        EventState::Open => (),
+    }
+
+    if info.x >= canvas_state.get_size().0 as u32 || info.y >= canvas_state.get_size().1 as u32 {
         return Err(error::ErrorBadRequest("invalid coordinates"));
     }
 
-    if user.cooldown - time > 0 {
+    if info.color as usize >= canvas_state.get_palette().len() {
+        return Err(error::ErrorBadRequest("invalid color"));
+    }
+
+    if canvas_state.is_retired(info.color) {
+        return Err(error::ErrorBadRequest("color retired"));
+    }
+
+    if let Some(region) = canvas_state.region_at(info.x as usize, info.y as usize) {
+        if user.role < Role::Admin {
+            return Err(error::ErrorForbidden(format!(
+                "pixel is in protected region \"{}\"",
+                region.name()
+            )));
+        }
+    }
+
+    let user_cooldown = canvas_state.user_cooldown(user_id);
+    if user_cooldown - time > 0 {
         return Err(error::ErrorBadRequest(format!(
             "cooldown not over : {}s",
-            user.cooldown - time
+            user_cooldown - time
         )));
     }
 
@@ -78,43 +326,60 @@ async fn draw(
         return Err(error::ErrorBadRequest("unverified"));
     }
 
-    appstate
-        .draw(info.x as usize, info.y as usize, user_id, info.color)
-        .map_err(|err| error::ErrorInternalServerError(format!("appstate error: {}", err)))?;
+    if user.banned {
+        return Err(error::ErrorForbidden("banned"));
+    }
+
+    let cooldown = canvas_state.cooldown();
 
     appstate
-        .try_update(&database)
-        .map_err(|err| eprintln!("appstate error: {}", err))
-        .ok();
+        .draw(
+            &canvas.0,
+            info.x as usize,
+            info.y as usize,
+            user_id,
+            info.color,
+        )
+        .map_err(|err| error::ErrorInternalServerError(format!("appstate error: {}", err)))?;
 
-    Ok(HttpResponse::Ok().json(appstate.cooldown()))
+    Ok(cooldown)
 }
 
+#[routes]
 #[get("/api/size")]
-async fn get_size(appstate: web::Data<RwLock<AppState>>) -> Result<HttpResponse, Error> {
+#[get("/api/c/{canvas}/size")]
+async fn get_size(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    Ok(HttpResponse::Ok().json(appstate.get_size()))
+    Ok(HttpResponse::Ok().json(find_canvas(&appstate, &canvas)?.get_size()))
 }
 
+#[routes]
 #[get("/api/username/{x}/{y}")]
+#[get("/api/c/{canvas}/username/{x}/{y}")]
 async fn get_username(
     appstate: web::Data<RwLock<AppState>>,
-    path: web::Path<(u32, u32)>,
+    canvas: CanvasName,
+    path: web::Path<PixelPath>,
 ) -> Result<HttpResponse, Error> {
-    let (x, y) = path.into_inner();
+    let PixelPath { x, y } = path.into_inner();
 
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    if x >= appstate.get_size().0 as u32 || y >= appstate.get_size().1 as u32 {
+    let canvas = find_canvas(&appstate, &canvas)?;
+
+    if x >= canvas.get_size().0 as u32 || y >= canvas.get_size().1 as u32 {
         return Err(error::ErrorBadRequest("invalid coordinates"));
     }
 
-    Ok(HttpResponse::Ok().body(appstate.get_username_from_pixel(x as usize, y as usize)))
+    Ok(HttpResponse::Ok().body(appstate.get_username_from_pixel(canvas, x as usize, y as usize)))
 }
 
 #[get("/api/users/count")]
@@ -126,13 +391,18 @@ async fn get_users_count(appstate: web::Data<RwLock<AppState>>) -> Result<HttpRe
     Ok(HttpResponse::Ok().json(appstate.user_length()))
 }
 
+#[routes]
 #[get("/api/users/connected")]
-async fn get_users_connected(appstate: web::Data<RwLock<AppState>>) -> Result<HttpResponse, Error> {
+#[get("/api/c/{canvas}/users/connected")]
+async fn get_users_connected(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    Ok(HttpResponse::Ok().json(appstate.get_users_connected()))
+    Ok(HttpResponse::Ok().json(find_canvas(&appstate, &canvas)?.get_users_connected()))
 }
 
 #[get("/api/leaderboard")]
@@ -143,3 +413,62 @@ async fn get_leaderboard(appstate: web::Data<RwLock<AppState>>) -> Result<HttpRe
 
     Ok(HttpResponse::Ok().json(appstate.get_leaderboard()))
 }
+
+#[routes]
+#[get("/api/regions")]
+#[get("/api/c/{canvas}/regions")]
+async fn get_regions(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    Ok(HttpResponse::Ok().json(find_canvas(&appstate, &canvas)?.get_regions()))
+}
+
+#[get("/api/regions/{region_id}/mask.png")]
+async fn get_region_mask(
+    database: web::Data<Database>,
+    path: web::Path<u32>,
+) -> Result<HttpResponse, Error> {
+    let mask = database
+        .get_region_mask(path.into_inner())
+        .map_err(|err| match err {
+            DatabaseError::NoSuchRow => error::ErrorNotFound("no such region"),
+            _ => error::ErrorInternalServerError("database error"),
+        })?
+        .ok_or_else(|| error::ErrorNotFound("region has no mask"))?;
+
+    Ok(HttpResponse::Ok().content_type("image/png").body(mask))
+}
+
+#[routes]
+#[get("/api/status")]
+#[get("/api/c/{canvas}/status")]
+async fn get_status(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    let status = find_canvas(&appstate, &canvas)?.event_status(Utc::now().timestamp());
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[routes]
+#[get("/api/palette")]
+#[get("/api/c/{canvas}/palette")]
+async fn get_palette(
+    appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+) -> Result<HttpResponse, Error> {
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    Ok(HttpResponse::Ok().json(find_canvas(&appstate, &canvas)?.get_palette_info()))
+}
@@ -1,15 +1,16 @@
 use std::sync::RwLock;
 
-use actix_web::{error, get, post, web, Error, HttpRequest, HttpResponse};
+use actix_web::{error, get, post, routes, web, Error, HttpRequest, HttpResponse};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use jsonwebtoken::{encode, Algorithm, Header};
 use rand::Rng;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::database;
-use crate::models::appstate::AppState;
-use crate::models::user::User;
-use crate::routes::utils::{token_to_id, Claims};
+use crate::database::{self, DatabaseError};
+use crate::models::appstate::{AppState, RESET_TOKEN_LIFETIME_SEC, VERIFICATION_CODE_LIFETIME_SEC};
+use crate::models::mailer::MailerError;
+use crate::models::user::{Role, User};
+use crate::routes::utils::{find_canvas, token_to_id, CanvasName, Claims};
 
 #[derive(Deserialize)]
 struct LoginInfo {
@@ -24,6 +25,39 @@ struct SignupInfo {
     email: String,
 }
 
+#[derive(Deserialize)]
+struct RefreshInfo {
+    refresh_token: String,
+}
+
+/// Access token to authenticate with, and refresh token to get the next one once it expires
+#[derive(Serialize)]
+struct Session {
+    access_token: String,
+    refresh_token: String,
+    expires_in: i64,
+}
+
+#[derive(Deserialize)]
+struct ForgotPasswordInfo {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ResetPasswordInfo {
+    token: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+struct Profile<'a> {
+    #[serde(flatten)]
+    user: &'a User,
+    role: Role,
+    banned: bool,
+    cooldown: i64,
+}
+
 #[derive(Deserialize)]
 pub struct ProfileEdit {
     pub username: String,
@@ -31,6 +65,50 @@ pub struct ProfileEdit {
     pub current_password: String,
 }
 
+const ACCESS_TOKEN_LIFETIME_SEC: i64 = 15 * 60;
+const REFRESH_TOKEN_LIFETIME_SEC: i64 = 30 * 24 * 60 * 60;
+
+/// Issues a new access token and refresh token to a user
+fn new_session(
+    appstate: &AppState,
+    database: &database::Database,
+    user_id: u16,
+) -> Result<Session, Error> {
+    let user = appstate
+        .get_user(user_id)
+        .ok_or_else(|| error::ErrorUnauthorized("invalid user"))?;
+
+    let now = Utc::now();
+    let claims = Claims {
+        id: user_id,
+        role: user.role,
+        ver: user.token_version,
+        exp: (now + Duration::seconds(ACCESS_TOKEN_LIFETIME_SEC)).timestamp() as usize,
+    };
+
+    let (kid, key) = appstate.jwt_keys().encoding_key();
+    let mut header = Header::new(Algorithm::HS512);
+    header.kid = Some(kid.to_string());
+
+    let access_token = encode(&header, &claims, &key)
+        .map_err(|_| error::ErrorInternalServerError("token encoding error"))?;
+
+    let refresh_token = random_token();
+    database
+        .create_refresh_token(
+            user_id,
+            &refresh_token,
+            now.timestamp() + REFRESH_TOKEN_LIFETIME_SEC,
+        )
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    Ok(Session {
+        access_token,
+        refresh_token,
+        expires_in: ACCESS_TOKEN_LIFETIME_SEC,
+    })
+}
+
 #[post("/api/login")]
 async fn login(
     appstate: web::Data<RwLock<AppState>>,
@@ -42,76 +120,227 @@ async fn login(
         .map_err(|_| error::ErrorInternalServerError("database error"))?
         .ok_or_else(|| error::ErrorUnauthorized("invalid credentials"))?;
 
-    let claims = Claims {
-        id: user_id,
-        exp: (Utc::now() + Duration::days(7)).timestamp() as usize,
-    };
+    let appstate = appstate
+        .read()
+        .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+    Ok(HttpResponse::Ok().json(new_session(&appstate, &database, user_id)?))
+}
+
+/// Trades a refresh token for a new session, the refresh token can't be used again
+#[post("/api/token/refresh")]
+async fn refresh_session(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<database::Database>,
+    info: web::Json<RefreshInfo>,
+) -> Result<HttpResponse, Error> {
+    let user_id = database
+        .take_refresh_token(&info.refresh_token)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?
+        .ok_or_else(|| error::ErrorUnauthorized("invalid or expired refresh token"))?;
 
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    let token = encode(
-        &Header::new(Algorithm::HS512),
-        &claims,
-        &EncodingKey::from_secret(appstate.jwt_secret().as_bytes()),
-    )
-    .map_err(|_| error::ErrorInternalServerError("token encoding error"))?;
+    Ok(HttpResponse::Ok().json(new_session(&appstate, &database, user_id)?))
+}
 
-    Ok(HttpResponse::Ok().body(token))
+/// Ends a session. Its access token keeps working until it expires
+#[post("/api/logout")]
+async fn logout(
+    database: web::Data<database::Database>,
+    info: web::Json<RefreshInfo>,
+) -> Result<HttpResponse, Error> {
+    database
+        .take_refresh_token(&info.refresh_token)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+    Ok(HttpResponse::Ok().body("ok"))
 }
 
-#[post("/api/signup")]
-async fn signup(
+/// Ends every session of the user, revoking their access tokens right away
+#[post("/api/logout/all")]
+async fn logout_all(
     appstate: web::Data<RwLock<AppState>>,
     database: web::Data<database::Database>,
-    info: web::Json<SignupInfo>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let mut appstate = appstate
         .write()
         .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
 
-    if !appstate.email_regex().is_match(&info.email) {
-        return Err(error::ErrorBadRequest("Invalid email format"));
-    }
+    let user_id = token_to_id(req, &appstate)?;
+    appstate
+        .revoke_tokens(user_id, &database)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
 
-    if info.username.len() < 3 || info.username.len() > 15 {
-        return Err(error::ErrorBadRequest(
-            "username must be between 3 and 15 characters",
-        ));
-    }
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+#[post("/api/signup")]
+async fn signup(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<database::Database>,
+    info: web::Json<SignupInfo>,
+) -> Result<HttpResponse, Error> {
+    let (mailer, verification_code) = {
+        let mut appstate = appstate
+            .write()
+            .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+        appstate
+            .check_new_email(&info.email)
+            .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+
+        if info.username.len() < 3 || info.username.len() > 15 {
+            return Err(error::ErrorBadRequest(
+                "username must be between 3 and 15 characters",
+            ));
+        }
+
+        check_password_length(&info.password)?;
+
+        if appstate.is_username_taken(&info.username) {
+            return Err(error::ErrorConflict("username taken"));
+        }
+
+        let verification_code = random_token();
+
+        let user_id = database
+            .signup(
+                &info.username,
+                &info.password,
+                &info.email,
+                &verification_code,
+                Utc::now().timestamp() + VERIFICATION_CODE_LIFETIME_SEC,
+            )
+            .map_err(|_| error::ErrorInternalServerError("database error"))?;
+
+        // Nobody could verify their account without mail, so users are verified right away
+        let verified = !appstate.sends_mail();
+        if verified {
+            database
+                .set_verified(user_id, true)
+                .map_err(|_| error::ErrorInternalServerError("database error"))?;
+        }
+
+        let user = User::new(info.username.clone(), 0, verified);
+
+        appstate.insert_user(user_id, user);
+        if verified {
+            return Ok(HttpResponse::Ok().body("ok"));
+        }
+
+        (appstate.mailer(), verification_code)
+    };
+
+    let email = info.into_inner().email;
+    web::block(move || mailer.send_verification_mail(&email, &verification_code))
+        .await
+        .map_err(|_| error::ErrorInternalServerError("blocking error"))?
+        .map_err(|err| {
+            eprintln!("Error sending verification email: {}", err);
+            error::ErrorInternalServerError("email error")
+        })?;
 
-    if info.password.len() < 8 || info.password.len() > 128 {
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+fn check_password_length(password: &str) -> Result<(), Error> {
+    if password.len() < 8 || password.len() > 128 {
         return Err(error::ErrorBadRequest(
             "password must be between 8 and 128 characters",
         ));
     }
 
-    if appstate.is_username_taken(&info.username) {
-        return Err(error::ErrorConflict("username taken"));
-    }
+    Ok(())
+}
 
-    let verification_code = rand::thread_rng()
-        .sample_iter(rand::distributions::Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect::<String>();
+/// Emails a password reset link. The lookup and the email happen after answering, so the
+/// answer and its timing are the same whether the email belongs to a user or not
+#[post("/api/password/forgot")]
+async fn forgot_password(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<database::Database>,
+    info: web::Json<ForgotPasswordInfo>,
+) -> Result<HttpResponse, Error> {
+    let now = Utc::now().timestamp();
+    let mailer = {
+        let mut appstate = appstate
+            .write()
+            .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+        if !appstate.email_regex().is_match(&info.email) {
+            return Err(error::ErrorBadRequest("Invalid email format"));
+        }
+
+        if !appstate.allow_password_reset(&info.email, now) {
+            return Err(error::ErrorTooManyRequests(
+                "too many reset requests for this email, try again later",
+            ));
+        }
+
+        appstate.mailer()
+    };
 
-    let user_id = database
-        .signup(
-            &info.username,
-            &info.password,
-            &info.email,
-            &verification_code,
-        )
-        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+    let email = info.into_inner().email;
+    actix_web::rt::spawn(async move {
+        let result = web::block(move || -> Result<(), String> {
+            let user = database
+                .get_user_by_email(&email)
+                .map_err(|err| err.to_string())?;
+            let Some((user_id, username)) = user else {
+                return Ok(());
+            };
+
+            let token = random_token();
+            database
+                .create_password_reset(user_id, &token, now + RESET_TOKEN_LIFETIME_SEC)
+                .map_err(|err| err.to_string())?;
+            mailer
+                .send_password_reset_mail(&email, &username, &token)
+                .map_err(|err| err.to_string())
+        })
+        .await;
+
+        match result {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => eprintln!("Error sending password reset: {}", err),
+            Err(err) => eprintln!("Error running password reset: {}", err),
+        }
+    });
+
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+/// Sets a new password and logs the user out of every device
+#[post("/api/password/reset")]
+async fn reset_password(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<database::Database>,
+    info: web::Json<ResetPasswordInfo>,
+) -> Result<HttpResponse, Error> {
+    check_password_length(&info.password)?;
 
-    let user = User::new(info.username.clone(), 0, false);
+    let user_id = database
+        .reset_password(&info.token, &info.password)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?
+        .ok_or_else(|| error::ErrorBadRequest("invalid or expired reset token"))?;
 
-    appstate.insert_user(user_id, user);
     appstate
-        .send_verification_mail(&info.email, &verification_code)
-        .map_err(|_| error::ErrorInternalServerError("email error"))?;
+        .write()
+        .map_err(|_| error::ErrorInternalServerError("appstate write error"))?
+        .revoke_tokens(user_id, &database)
+        .map_err(|_| error::ErrorInternalServerError("database error"))?;
 
     Ok(HttpResponse::Ok().body("ok"))
 }
@@ -124,7 +353,8 @@ async fn verify(
 ) -> Result<HttpResponse, Error> {
     let user_id = database
         .verify(&token)
-        .map_err(|_| error::ErrorInternalServerError("database error"))?;
+        .map_err(|_| error::ErrorInternalServerError("database error"))?
+        .ok_or_else(|| error::ErrorBadRequest("invalid or expired verification link"))?;
 
     let mut appstate = appstate
         .write()
@@ -139,23 +369,95 @@ async fn verify(
     Ok(HttpResponse::Ok().body("Account verified"))
 }
 
+/// Emails a new verification link to the logged in user, the previous link stops working
+#[post("/api/verify/resend")]
+async fn resend_verification(
+    appstate: web::Data<RwLock<AppState>>,
+    database: web::Data<database::Database>,
+    req: HttpRequest,
+) -> Result<HttpResponse, Error> {
+    let now = Utc::now().timestamp();
+    let (user_id, mailer) = {
+        let mut appstate = appstate
+            .write()
+            .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
+
+        let user_id = token_to_id(req, &appstate)?;
+        if appstate.get_user(user_id).is_some_and(|user| user.verified) {
+            return Err(error::ErrorConflict(
+                "account is not waiting for verification",
+            ));
+        }
+        if !appstate.allow_verification_resend(user_id, now) {
+            return Err(error::ErrorTooManyRequests(
+                "too many verification emails, try again later",
+            ));
+        }
+
+        (user_id, appstate.mailer())
+    };
+
+    let sent = web::block(
+        move || -> Result<Result<bool, MailerError>, DatabaseError> {
+            let Some(email) = database.get_pending_email(user_id)? else {
+                return Ok(Ok(false));
+            };
+
+            let verification_code = random_token();
+            database.renew_verification_code(
+                user_id,
+                &verification_code,
+                now + VERIFICATION_CODE_LIFETIME_SEC,
+            )?;
+            Ok(mailer
+                .send_verification_mail(&email, &verification_code)
+                .map(|_| true))
+        },
+    )
+    .await
+    .map_err(|_| error::ErrorInternalServerError("blocking error"))?
+    .map_err(|_| error::ErrorInternalServerError("database error"))?
+    .map_err(|err| {
+        eprintln!("Error sending verification email: {}", err);
+        error::ErrorInternalServerError("email error")
+    })?;
+
+    if !sent {
+        return Err(error::ErrorConflict(
+            "account is not waiting for verification",
+        ));
+    }
+
+    Ok(HttpResponse::Ok().body("ok"))
+}
+
+#[routes]
 #[get("/api/profile/me")]
+#[get("/api/c/{canvas}/profile/me")]
 async fn get_profile(
     appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
     req: HttpRequest,
 ) -> Result<HttpResponse, Error> {
     let appstate = appstate
         .read()
         .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
 
-    let user_id = token_to_id(req, appstate.jwt_secret().as_bytes())?;
+    let user_id = token_to_id(req, &appstate)?;
     let user = appstate
         .get_user(user_id)
         .ok_or_else(|| error::ErrorBadRequest("invalid user"))?;
 
-    Ok(HttpResponse::Ok().json(user))
+    Ok(HttpResponse::Ok().json(Profile {
+        user,
+        role: user.role,
+        banned: user.banned,
+        cooldown: find_canvas(&appstate, &canvas)?.user_cooldown(user_id),
+    }))
 }
 
+/// Changing the password logs the user out of every device, and answers with a new session
+/// for this one
 #[post("/api/profile/edit")]
 async fn edit_profile(
     appstate: web::Data<RwLock<AppState>>,
@@ -167,7 +469,7 @@ async fn edit_profile(
         .write()
         .map_err(|_| error::ErrorInternalServerError("appstate write error"))?;
 
-    let user_id = token_to_id(req, appstate.jwt_secret().as_bytes())?;
+    let user_id = token_to_id(req, &appstate)?;
 
     if info.username.len() < 3 || info.username.len() > 15 {
         return Err(error::ErrorBadRequest(
@@ -197,9 +499,17 @@ async fn edit_profile(
 
     user.username = info.username.clone();
 
+    let password_changed = !info.password.trim().is_empty();
     database
         .edit_profile(user_id, &info.into_inner())
         .map_err(|_| error::ErrorBadRequest("database error"))?;
 
+    if password_changed {
+        appstate
+            .revoke_tokens(user_id, &database)
+            .map_err(|_| error::ErrorInternalServerError("database error"))?;
+        return Ok(HttpResponse::Ok().json(new_session(&appstate, &database, user_id)?));
+    }
+
     Ok(HttpResponse::Ok().body("ok"))
 }
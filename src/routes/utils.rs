@@ -1,14 +1,25 @@
-use actix_web::{error, Error, HttpRequest};
-use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use std::future::{ready, Ready};
+
+use actix_web::{dev, error, Error, FromRequest, HttpRequest};
+use jsonwebtoken::{decode, decode_header, Algorithm, Validation};
 use serde_derive::{Deserialize, Serialize};
 
+use crate::models::appstate::AppState;
+use crate::models::canvas::{Canvas, DEFAULT_CANVAS};
+use crate::models::user::Role;
+
 #[derive(Deserialize, Serialize)]
 pub struct Claims {
     pub id: u16,
+    #[serde(default)]
+    pub role: Role,
+    /// `User::token_version` when the token was issued
+    #[serde(default)]
+    pub ver: u32,
     pub exp: usize,
 }
 
-pub fn token_to_id(req: HttpRequest, key: &[u8]) -> Result<u16, Error> {
+pub fn token_to_id(req: HttpRequest, appstate: &AppState) -> Result<u16, Error> {
     let header = req
         .headers()
         .get("Authorization")
@@ -24,11 +35,80 @@ pub fn token_to_id(req: HttpRequest, key: &[u8]) -> Result<u16, Error> {
 
     let token = header_str.trim_start_matches("Bearer ");
 
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(key),
-        &Validation::new(Algorithm::HS512),
-    )
-    .map(|data| data.claims.id)
-    .map_err(|_| error::ErrorUnauthorized("invalid token"))
+    decode_token(token, appstate)
+}
+
+/// Id of the user of a valid access token, issued since their last logout of every device
+pub fn decode_token(token: &str, appstate: &AppState) -> Result<u16, Error> {
+    decode_claims(token, appstate).map(|(claims, _)| claims.id)
+}
+
+/// Claims of a valid access token, see `decode_token`, with the id of the key that signed it
+pub fn decode_claims(token: &str, appstate: &AppState) -> Result<(Claims, Option<String>), Error> {
+    let header = decode_header(token).map_err(|_| error::ErrorUnauthorized("invalid token"))?;
+    let key = appstate
+        .jwt_keys()
+        .decoding_key(header.kid.as_deref())
+        .ok_or_else(|| error::ErrorUnauthorized("retired token key"))?;
+
+    let claims = decode::<Claims>(token, &key, &Validation::new(Algorithm::HS512))
+        .map(|data| data.claims)
+        .map_err(|_| error::ErrorUnauthorized("invalid token"))?;
+
+    let user = appstate
+        .get_user(claims.id)
+        .ok_or_else(|| error::ErrorUnauthorized("invalid user"))?;
+
+    if user.token_version != claims.ver {
+        return Err(error::ErrorUnauthorized("revoked token"));
+    }
+
+    Ok((claims, header.kid))
+}
+
+/// Id of the requesting user if their role is at least `role`. The role is read from the
+/// app state rather than from the token so that demotions and bans apply immediately
+pub fn require_role(req: &HttpRequest, appstate: &AppState, role: Role) -> Result<u16, Error> {
+    let user_id = token_to_id(req.clone(), appstate)?;
+
+    let user = appstate
+        .get_user(user_id)
+        .ok_or_else(|| error::ErrorUnauthorized("invalid user"))?;
+
+    if user.banned || user.role < role {
+        return Err(error::ErrorForbidden(format!(
+            "{} role required",
+            role.as_str()
+        )));
+    }
+
+    Ok(user_id)
+}
+
+/// Canvas selected by the `/api/c/{canvas}/` prefix, or the default one without it
+pub struct CanvasName(pub String);
+
+impl FromRequest for CanvasName {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let name = req.match_info().get("canvas").unwrap_or(DEFAULT_CANVAS);
+        ready(Ok(CanvasName(name.to_string())))
+    }
+}
+
+pub fn find_canvas<'a>(appstate: &'a AppState, name: &CanvasName) -> Result<&'a Canvas, Error> {
+    appstate
+        .get_canvas(&name.0)
+        .ok_or_else(|| error::ErrorNotFound("no such canvas"))
+}
+
+pub fn find_canvas_mut<'a>(
+    appstate: &'a mut AppState,
+    name: &CanvasName,
+) -> Result<&'a mut Canvas, Error> {
+    appstate
+        .get_canvas_mut(&name.0)
+        .ok_or_else(|| error::ErrorNotFound("no such canvas"))
 }
@@ -1,22 +1,265 @@
 use std::sync::RwLock;
+use std::time::{Duration, Instant};
 
 use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
-use actix_web::{error, get, web, Error, HttpRequest, HttpResponse};
+use actix_web::{error, routes, web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use serde_derive::Serialize;
+use chrono::Utc;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::models::appstate::AppState;
+use crate::models::appstate::{AppState, AppStateError};
+use crate::models::event::EventState;
+use crate::routes::place::{place_pixel, DrawInfo};
+use crate::routes::utils::{decode_claims, find_canvas, CanvasName};
 
 #[derive(Message, Clone, Copy, Serialize)]
 #[rtype(result = "()")]
 pub struct MessageUpdate {
+    pub seq: u64,
     pub x: usize,
     pub y: usize,
     pub color: u8,
 }
 
+impl MessageUpdate {
+    /// Packs the update as little-endian `seq: u32, x: u16, y: u16, color: u8`,
+    /// keeping only the low 32 bits of the sequence number
+    fn write_binary(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&(self.seq as u32).to_le_bytes());
+        buffer.extend_from_slice(&(self.x as u16).to_le_bytes());
+        buffer.extend_from_slice(&(self.y as u16).to_le_bytes());
+        buffer.push(self.color);
+    }
+}
+
+/// Closes the connection with a reason, e.g. when the server shuts down
+#[derive(Message, Clone)]
+#[rtype(result = "()")]
+pub struct CloseMessage(pub String);
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum UpdateFormat {
+    #[default]
+    Json,
+    Binary,
+}
+
+#[derive(Deserialize)]
+struct WebSocketOptions {
+    #[serde(default)]
+    format: UpdateFormat,
+    /// Milliseconds between binary frames, each carrying every update received in between
+    batch: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    Unsubscribe,
+    Auth {
+        token: String,
+    },
+    Draw {
+        id: u64,
+        #[serde(flatten)]
+        pixel: DrawInfo,
+    },
+}
+
+#[derive(Message, Clone, Serialize)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Error {
+        message: String,
+    },
+    Authenticated {
+        user_id: u16,
+    },
+    Ack {
+        id: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cooldown: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The canvas grew, the new area is filled with the `blank` color
+    Resize {
+        width: usize,
+        height: usize,
+        blank: u8,
+    },
+    State {
+        state: EventState,
+        opens_at: Option<i64>,
+        closes_at: Option<i64>,
+    },
+    Palette {
+        colors: Vec<String>,
+        retired: Vec<u8>,
+    },
+    /// Seconds left until the canvas switches to `state`
+    Countdown {
+        state: EventState,
+        seconds: i64,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct Viewport {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Viewport {
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(15);
+const MIN_BATCH_MS: u64 = 10;
+const MAX_BATCH_MS: u64 = 5000;
+
+/// Access token a connection authenticated with. Draws are refused once it expires, its key is
+/// retired or the user's token version changes, until the client authenticates again
+struct SocketAuth {
+    user_id: u16,
+    token_version: u32,
+    expires_at: i64,
+    kid: Option<String>,
+}
+
 pub struct PlaceWebSocketConnection {
     appstate: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+    auth: Option<SocketAuth>,
+    format: UpdateFormat,
+    batch: Option<Duration>,
+    pending: Vec<u8>,
+    heartbeat: Instant,
+    viewport: Option<Viewport>,
+}
+
+impl PlaceWebSocketConnection {
+    fn send(&self, msg: &ServerMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        serde_json::to_string(msg)
+            .map(|text| ctx.text(text))
+            .unwrap_or_else(|err| eprintln!("Error serializing server message: {}", err));
+    }
+
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let msg = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(msg) => msg,
+            Err(err) => {
+                let message = format!("invalid message: {}", err);
+                return self.send(&ServerMessage::Error { message }, ctx);
+            }
+        };
+
+        match msg {
+            ClientMessage::Subscribe {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                self.viewport = Some(Viewport {
+                    x,
+                    y,
+                    width,
+                    height,
+                })
+            }
+            ClientMessage::Unsubscribe => self.viewport = None,
+            ClientMessage::Auth { token } => {
+                let msg = match self.authenticate(&token) {
+                    Ok(auth) => {
+                        let user_id = auth.user_id;
+                        self.auth = Some(auth);
+                        ServerMessage::Authenticated { user_id }
+                    }
+                    Err(err) => ServerMessage::Error {
+                        message: err.to_string(),
+                    },
+                };
+                self.send(&msg, ctx);
+            }
+            ClientMessage::Draw { id, pixel } => {
+                let msg = match self.draw(&pixel) {
+                    Ok(cooldown) => ServerMessage::Ack {
+                        id,
+                        cooldown: Some(cooldown),
+                        error: None,
+                    },
+                    Err(err) => ServerMessage::Ack {
+                        id,
+                        cooldown: None,
+                        error: Some(err.to_string()),
+                    },
+                };
+                self.send(&msg, ctx);
+            }
+        }
+    }
+
+    fn authenticate(&self, token: &str) -> Result<SocketAuth, Error> {
+        let appstate = self
+            .appstate
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+
+        let (claims, kid) = decode_claims(token, &appstate)?;
+
+        Ok(SocketAuth {
+            user_id: claims.id,
+            token_version: claims.ver,
+            expires_at: claims.exp as i64,
+            kid,
+        })
+    }
+
+    fn draw(&self, pixel: &DrawInfo) -> Result<u16, Error> {
+        let auth = self
+            .auth
+            .as_ref()
+            .ok_or_else(|| error::ErrorUnauthorized("not authenticated"))?;
+
+        if Utc::now().timestamp() >= auth.expires_at {
+            return Err(error::ErrorUnauthorized(
+                "token expired, send Auth with a new one",
+            ));
+        }
+
+        let mut appstate = self
+            .appstate
+            .write()
+            .map_err(|err| error::ErrorInternalServerError(format!("appstate error: {}", err)))?;
+
+        if !appstate.jwt_keys().has_key(auth.kid.as_deref()) {
+            return Err(error::ErrorUnauthorized(
+                "retired token key, send Auth with a new token",
+            ));
+        }
+        let current = appstate
+            .get_user(auth.user_id)
+            .is_some_and(|user| user.token_version == auth.token_version);
+        if !current {
+            return Err(error::ErrorUnauthorized("revoked token"));
+        }
+
+        place_pixel(&mut appstate, &self.canvas, auth.user_id, pixel)
+    }
 }
 
 impl Actor for PlaceWebSocketConnection {
@@ -24,14 +267,46 @@ impl Actor for PlaceWebSocketConnection {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         self.appstate
-            .write()
-            .map_err(|_| eprintln!("Error writing to app state"))
+            .read()
+            .map_err(|_| eprintln!("Error reading app state"))
             .and_then(|appstate| {
                 appstate
-                    .add_session(ctx.address())
+                    .get_canvas(&self.canvas.0)
+                    .ok_or(AppStateError::NoSuchCanvasError)
+                    .and_then(|canvas| canvas.add_session(ctx.address()))
                     .map_err(|err| eprintln!("Error adding session: {}", err))
             })
             .unwrap_or_else(|_| ctx.stop());
+
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+
+        if let Some(batch) = self.batch {
+            ctx.run_interval(batch, |act, ctx| {
+                if !act.pending.is_empty() {
+                    ctx.binary(std::mem::take(&mut act.pending));
+                }
+            });
+        }
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.appstate
+            .read()
+            .map_err(|_| eprintln!("Error reading app state"))
+            .and_then(|appstate| {
+                appstate
+                    .get_canvas(&self.canvas.0)
+                    .ok_or(AppStateError::NoSuchCanvasError)
+                    .and_then(|canvas| canvas.remove_session(&ctx.address()))
+                    .map_err(|err| eprintln!("Error removing session: {}", err))
+            })
+            .unwrap_or(());
     }
 }
 
@@ -39,30 +314,101 @@ impl Handler<MessageUpdate> for PlaceWebSocketConnection {
     type Result = ();
 
     fn handle(&mut self, msg: MessageUpdate, ctx: &mut Self::Context) {
-        serde_json::to_string(&msg)
-            .map_err(|_| ctx.text("Error serializing update message"))
-            .map(|text| ctx.text(text))
-            .unwrap_or(());
+        if let Some(viewport) = self.viewport {
+            if !viewport.contains(msg.x, msg.y) {
+                return;
+            }
+        }
+
+        match self.format {
+            UpdateFormat::Json => serde_json::to_string(&msg)
+                .map_err(|_| ctx.text("Error serializing update message"))
+                .map(|text| ctx.text(text))
+                .unwrap_or(()),
+            UpdateFormat::Binary => {
+                msg.write_binary(&mut self.pending);
+                if self.batch.is_none() {
+                    ctx.binary(std::mem::take(&mut self.pending));
+                }
+            }
+        }
+    }
+}
+
+impl Handler<CloseMessage> for PlaceWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseMessage, ctx: &mut Self::Context) {
+        if !self.pending.is_empty() {
+            ctx.binary(std::mem::take(&mut self.pending));
+        }
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Away,
+            description: Some(msg.0),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<ServerMessage> for PlaceWebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) {
+        self.send(&msg, ctx);
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for PlaceWebSocketConnection {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        self.heartbeat = Instant::now();
+
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => ctx.text(text),
+            Ok(ws::Message::Text(text)) => self.handle_text(&text, ctx),
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(_) => ctx.stop(),
             _ => (),
         }
     }
 }
 
+#[routes]
 #[get("/api/ws")]
+#[get("/api/c/{canvas}/ws")]
 async fn ws_index(
     req: HttpRequest,
     stream: web::Payload,
     data: web::Data<RwLock<AppState>>,
+    canvas: CanvasName,
+    options: web::Query<WebSocketOptions>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(PlaceWebSocketConnection { appstate: data }, &req, stream)
+    {
+        let appstate = data
+            .read()
+            .map_err(|_| error::ErrorInternalServerError("appstate read error"))?;
+        find_canvas(&appstate, &canvas)?;
+    }
+
+    let batch = options
+        .batch
+        .filter(|_| options.format == UpdateFormat::Binary)
+        .map(|batch| Duration::from_millis(batch.clamp(MIN_BATCH_MS, MAX_BATCH_MS)));
+
+    let connection = PlaceWebSocketConnection {
+        appstate: data,
+        canvas,
+        auth: None,
+        format: options.format,
+        batch,
+        pending: Vec::new(),
+        heartbeat: Instant::now(),
+        viewport: None,
+    };
+
+    ws::start(connection, &req, stream)
         .map_err(|_| error::ErrorInternalServerError("websocket error"))
 }